@@ -2,14 +2,12 @@ use std::collections::Bound;
 use std::hash::Hash;
 use std::time::{Duration, Instant};
 
+use enum_as_inner::EnumAsInner;
 use parking_lot::Mutex;
 use tracing::debug;
 
-use lru::LruCache;
-
 use crate::dns::*;
-
-mod lru;
+use crate::lru::LruCache;
 
 #[derive(Debug, Eq, Clone, Hash, Ord, PartialEq, PartialOrd)]
 struct Key {
@@ -36,7 +34,7 @@ struct Value {
     pub soa: Option<Name>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, EnumAsInner)]
 pub enum Item {
     Negative {
         response_code: ResponseCode,
@@ -171,7 +169,6 @@ impl Cache {
                 soa = None;
                 (self.min_positive_ttl_secs, match rr.kind {
                     | RRK_CNAME
-                    | RRK_PTR
                     | RRK_SOA
                     => {
                         sub = SubKey::Unique;
@@ -257,10 +254,117 @@ mod tests {
             SubKey::RRData(RRData::Name("abc.def".parse().unwrap())),
             SubKey::RRData(RRData::Ipv4Addr(Ipv4Addr::UNSPECIFIED)),
             SubKey::RRData(RRData::Ipv6Addr(Ipv6Addr::UNSPECIFIED)),
+            SubKey::RRData(RRData::Unknown(vec![0xff; 100])),
         ];
         for v in data {
             assert!(v < SubKey::Last);
             assert!(key(v) < key(SubKey::Last));
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn unknown_rr_data() {
+        let cache = Cache::new(10, 3600, 0, 0, 0, Duration::ZERO, 0);
+        let now = Instant::now();
+        let name: Name = "example.com".parse().unwrap();
+        for data in [&b"\x01a"[..], b"\x01b"] {
+            cache.insert(name.clone(), RRK_NULL, RRC_IN, 60, now, Item::Positive(ResourceRecord {
+                name: name.clone(),
+                kind: RRK_NULL,
+                class: RRC_IN,
+                ttl_secs: 60,
+                data: RRData::Unknown(data.to_vec()),
+            }));
+        }
+
+        let items = cache.get(&name, RRK_NULL, RRC_IN, now, false);
+        let data: Vec<_> = items.into_iter()
+            .map(|i| i.into_positive().unwrap().data)
+            .collect();
+        assert_eq!(data, vec![
+            RRData::Unknown(b"\x01a".to_vec()),
+            RRData::Unknown(b"\x01b".to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn ptr_rrset() {
+        let cache = Cache::new(10, 3600, 0, 0, 0, Duration::ZERO, 0);
+        let now = Instant::now();
+        let name: Name = "4.3.2.1.in-addr.arpa".parse().unwrap();
+        for target in ["a.example.com", "b.example.com"] {
+            cache.insert(name.clone(), RRK_PTR, RRC_IN, 60, now, Item::Positive(ResourceRecord {
+                name: name.clone(),
+                kind: RRK_PTR,
+                class: RRC_IN,
+                ttl_secs: 60,
+                data: RRData::Ptr(target.parse().unwrap()),
+            }));
+        }
+
+        let items = cache.get(&name, RRK_PTR, RRC_IN, now, false);
+        let data: Vec<_> = items.into_iter()
+            .map(|i| i.into_positive().unwrap().data)
+            .collect();
+        assert_eq!(data, vec![
+            RRData::Ptr("a.example.com".parse().unwrap()),
+            RRData::Ptr("b.example.com".parse().unwrap()),
+        ]);
+    }
+
+    fn a(cache: &Cache, name: &str, now: Instant) {
+        let name: Name = name.parse().unwrap();
+        cache.insert(name.clone(), RRK_A, RRC_IN, 60, now, Item::Positive(ResourceRecord {
+            name,
+            kind: RRK_A,
+            class: RRC_IN,
+            ttl_secs: 60,
+            data: RRData::Ipv4Addr(Ipv4Addr::new(1, 2, 3, 4)),
+        }));
+    }
+
+    fn has_a(cache: &Cache, name: &str, now: Instant) -> bool {
+        !cache.get(&name.parse().unwrap(), RRK_A, RRC_IN, now, false).is_empty()
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let cache = Cache::new(2, 3600, 0, 0, 0, Duration::ZERO, 0);
+        let now = Instant::now();
+        a(&cache, "a.example.com", now);
+        a(&cache, "b.example.com", now);
+        assert!(has_a(&cache, "a.example.com", now));
+        a(&cache, "c.example.com", now);
+        assert!(has_a(&cache, "a.example.com", now));
+        assert!(!has_a(&cache, "b.example.com", now));
+        assert!(has_a(&cache, "c.example.com", now));
+
+        // Refreshing an entry doesn't evict the others.
+        a(&cache, "c.example.com", now);
+        assert!(has_a(&cache, "a.example.com", now));
+        assert!(has_a(&cache, "c.example.com", now));
+    }
+
+    #[test]
+    fn evict_after_cname_replaces_a() {
+        let cache = Cache::new(2, 3600, 0, 0, 0, Duration::ZERO, 0);
+        let now = Instant::now();
+        let name: Name = "a.example.com".parse().unwrap();
+        a(&cache, "a.example.com", now);
+        cache.insert(name.clone(), RRK_CNAME, RRC_IN, 60, now, Item::Positive(ResourceRecord {
+            name: name.clone(),
+            kind: RRK_CNAME,
+            class: RRC_IN,
+            ttl_secs: 60,
+            data: RRData::Name("b.example.com".parse().unwrap()),
+        }));
+        assert!(!has_a(&cache, "a.example.com", now));
+
+        // The removed A RR must not be picked for eviction.
+        a(&cache, "b.example.com", now);
+        a(&cache, "c.example.com", now);
+        assert!(cache.get(&name, RRK_CNAME, RRC_IN, now, false).is_empty());
+        assert!(has_a(&cache, "b.example.com", now));
+        assert!(has_a(&cache, "c.example.com", now));
+    }
+}
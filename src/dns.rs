@@ -1,19 +1,22 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::fmt::Write;
 use std::io::Read;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::error::Error as StdError;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
 use std::time::Duration;
 
-use anyhow::{anyhow, bail, Result};
 use bit_field::BitField;
 use byteorder::{BE, ReadBytesExt};
 use byteorder::ByteOrder;
-use bytes::{Buf, BufMut};
+use bytes::{Buf, BufMut, Bytes};
 use enum_as_inner::EnumAsInner;
-use tracing::trace;
 
-pub type ResponseCode = u8;
+/// Full 12-bit response code. The upper 8 bits are carried in the OPT RR.
+pub type ResponseCode = u16;
 pub const RCODE_NO_ERROR: ResponseCode = 0;
 pub const RCODE_FORMAT_ERROR: ResponseCode = 1;
 pub const RCODE_SERVER_FAILURE: ResponseCode = 2;
@@ -24,6 +27,7 @@ pub const RCODE_YX_DOMAIN: ResponseCode = 6;
 pub const RCODE_YX_RR_SET: ResponseCode = 7;
 pub const RCODE_NOT_AUTH: ResponseCode = 9;
 pub const RCODE_NOT_ZONE: ResponseCode = 10;
+pub const RCODE_BAD_VERSION: ResponseCode = 16;
 pub const RCODE_BAD_SIGNATURE: ResponseCode = 16;
 pub const RCODE_BAD_KEY: ResponseCode = 17;
 pub const RCODE_BAD_TIME: ResponseCode = 18;
@@ -51,6 +55,10 @@ pub const RRK_MINFO: RRKind = 14;
 pub const RRK_MX: RRKind = 15;
 pub const RRK_TXT: RRKind = 16;
 pub const RRK_AAAA: RRKind = 28;
+pub const RRK_SRV: RRKind = 33;
+pub const RRK_OPT: RRKind = 41;
+pub const RRK_SVCB: RRKind = 64;
+pub const RRK_HTTPS: RRKind = 65;
 pub const RRKQ_AXFR: RRKind = 252;
 pub const RRKQ_MAILB: RRKind = 253;
 pub const RRKQ_MAILA: RRKind = 254;
@@ -64,105 +72,286 @@ pub const RRC_HS: RRClass = 4;
 pub const RRC_NONE: RRClass = 254;
 pub const RRCQ_ANY: RRClass = 255;
 
-#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
-pub struct Name(String);
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// Packet is shorter than the header.
+    TruncatedHeader,
+    /// Packet ended in the middle of a field.
+    UnexpectedEnd,
+    /// Question count is not 1.
+    BadQuestionCount(u16),
+    /// Unsupported label type.
+    BadLabel,
+    /// Compression pointer doesn't point backwards and therefore may form a loop.
+    PointerLoop,
+    NameTooLong,
+    /// RR data is not exactly the length specified by RDLENGTH.
+    RRDataLenMismatch { kind: RRKind },
+    BadRRData { kind: RRKind },
+    BadSvcParam(SvcParamKey),
+    /// Malformed or duplicate OPT RR.
+    BadOpt,
+    BadEdnsOption(EdnsOptionCode),
+    /// Section has more than 65535 RRs.
+    SectionTooLong,
+    RRDataTooLong { kind: RRKind },
+    CharStringTooLong,
+    /// Response code that doesn't fit into the header and there's no OPT RR to hold the extended
+    /// bits, or the code is out of range.
+    BadResponseCode(ResponseCode),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TruncatedHeader => write!(f, "truncated header"),
+            Self::UnexpectedEnd => write!(f, "unexpected end of packet"),
+            Self::BadQuestionCount(count) => write!(f, "bad question count {}", count),
+            Self::BadLabel => write!(f, "bad label"),
+            Self::PointerLoop => write!(f, "compression pointer loop"),
+            Self::NameTooLong => write!(f, "name too long"),
+            Self::RRDataLenMismatch { kind } =>
+                write!(f, "RR data length mismatch (kind {})", kind),
+            Self::BadRRData { kind } => write!(f, "bad RR data (kind {})", kind),
+            Self::BadSvcParam(key) => write!(f, "bad SVCB param (key {})", key),
+            Self::BadOpt => write!(f, "bad OPT RR"),
+            Self::BadEdnsOption(code) => write!(f, "bad EDNS option (code {})", code),
+            Self::SectionTooLong => write!(f, "section too long"),
+            Self::RRDataTooLong { kind } => write!(f, "RR data too long (kind {})", kind),
+            Self::CharStringTooLong => write!(f, "character-string too long"),
+            Self::BadResponseCode(code) => write!(f, "bad response code {}", code),
+        }
+    }
+}
+
+impl StdError for Error {}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Domain name. Labels are arbitrary octets (RFC 2181 section 11). Equality and hashing are
+/// ASCII case-insensitive, ordering is the canonical DNS name order (RFC 4034 section 6.1).
+#[derive(Clone, Default)]
+pub struct Name(
+    /// Uncompressed wire format without the terminating root label.
+    Bytes);
 
 impl Name {
+    /// Max length of a name in wire format including the root label.
+    pub const MAX_LEN: usize = 255;
+    pub const MAX_LABEL_LEN: usize = 63;
+    const MAX_LABEL_COUNT: usize = Self::MAX_LEN / 2;
+
+    /// Creates name from the labels listed from the leftmost one. Returns `None` if any of the
+    /// labels is empty or too long, or the name is too long.
+    pub fn from_labels<T: AsRef<[u8]>>(labels: impl IntoIterator<Item=T>) -> Option<Self> {
+        let mut r = Vec::new();
+        for label in labels {
+            let label = label.as_ref();
+            if label.is_empty() || label.len() > Self::MAX_LABEL_LEN {
+                return None;
+            }
+            r.push(label.len() as u8);
+            r.extend_from_slice(label);
+            if r.len() + 1 > Self::MAX_LEN {
+                return None;
+            }
+        }
+        Some(Self(r.into()))
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the name with the leftmost label removed. The parent of the root is the root.
     pub fn parent(&self) -> Name {
-        if let Some(i) = self.0.as_bytes().iter().position(|&c| c == b'.') {
-            Self(self.0[i + 1..].to_owned())
-        } else {
-            Default::default()
+        if self.is_root() {
+            return Self::default();
+        }
+        Self(self.0.slice(self.0[0] as usize + 1..))
+    }
+
+    /// Iterates over labels starting from the leftmost one.
+    pub fn labels(&self) -> impl DoubleEndedIterator<Item=&[u8]> + ExactSizeIterator {
+        let (offsets, len) = self.label_offsets();
+        (0..len).map(move |i| {
+            let i = offsets[i] as usize;
+            &self.0[i + 1..i + 1 + self.0[i] as usize]
+        })
+    }
+
+    pub fn label_count(&self) -> usize {
+        self.label_offsets().1
+    }
+
+    /// Whether this name is equal to `other` or is a subdomain of it.
+    pub fn is_subdomain_of(&self, other: &Name) -> bool {
+        other.is_root() || self.labels_wire()
+            .any(|s| s.len() == other.0.len() && s.eq_ignore_ascii_case(&other.0))
+    }
+
+    /// Whether the name is a valid host name, i.e. its labels contain only letters, digits and
+    /// hyphens and don't start or end with a hyphen (RFC 952, RFC 1123 section 2.1).
+    pub fn is_hostname(&self) -> bool {
+        self.labels().all(|l| {
+            l.iter().all(|&c| c.is_ascii_alphanumeric() || c == b'-')
+                && l.first() != Some(&b'-')
+                && l.last() != Some(&b'-')
+        })
+    }
+
+    /// Returns offsets of the label length octets and the label count.
+    fn label_offsets(&self) -> ([u8; Self::MAX_LABEL_COUNT], usize) {
+        let mut r = [0; Self::MAX_LABEL_COUNT];
+        let mut len = 0;
+        let mut i = 0;
+        while i < self.0.len() {
+            r[len] = i as u8;
+            len += 1;
+            i += self.0[i] as usize + 1;
         }
+        (r, len)
+    }
+
+    /// Iterates over the wire format suffixes starting at each label.
+    fn labels_wire(&self) -> impl Iterator<Item=&[u8]> {
+        let mut s = &self.0[..];
+        std::iter::from_fn(move || {
+            if s.is_empty() {
+                return None;
+            }
+            let r = s;
+            s = &s[s[0] as usize + 1..];
+            Some(r)
+        })
     }
 
+    /// Decodes the name at `cursor` which must be a suffix of `pkt`.
     fn decode<'a>(pkt: &'a [u8], cursor: &mut &'a [u8]) -> Result<Self> {
         let mut saved_cursor = None;
-        let mut r = String::new();
-        let mut hops = 0;
+        let mut r = Vec::new();
         loop {
-            let len = cursor.read_u8().map_err(|_| anyhow!("bad name"))?;
+            let label_pos = pkt.len() - cursor.len();
+            let len = cursor.read_u8().map_err(|_| Error::UnexpectedEnd)?;
             if len == 0 {
                 break;
             } else if len & 0xc0 == 0xc0 {
-                if hops == MAX_HOPS {
-                    bail!("bad name: too many hops");
-                }
-                hops += 1;
                 let pos = u16::from_be_bytes([
                     len & 0x3f,
-                    cursor.read_u8().map_err(|_| anyhow!("bad name"))?]) as usize;
+                    cursor.read_u8().map_err(|_| Error::UnexpectedEnd)?]) as usize;
+                // Only allow pointing backwards, this guarantees there are no loops.
+                if pos >= label_pos {
+                    return Err(Error::PointerLoop);
+                }
                 if saved_cursor.is_none() {
                     saved_cursor = Some(*cursor);
                 }
-                *cursor = pkt.get(pos..)
-                    .ok_or_else(|| anyhow!("bad name"))?;
+                *cursor = &pkt[pos..];
 
+            } else if len & 0xc0 != 0 {
+                return Err(Error::BadLabel);
             } else {
                 let len = len as usize;
                 if len >= cursor.len() {
-                    bail!("bad name");
+                    return Err(Error::UnexpectedEnd);
                 }
-                if !r.is_empty() {
-                    r.push('.');
+                if r.len() + len + 2 > Self::MAX_LEN {
+                    return Err(Error::NameTooLong);
                 }
-                Self::decode_label(&mut r, &cursor[..len])?;
+                r.push(len as u8);
+                r.extend_from_slice(&cursor[..len]);
                 cursor.advance(len);
             }
         }
         if let Some(sc) = saved_cursor {
             *cursor = sc;
         }
-        Ok(Self(r))
+        Ok(Self(r.into()))
     }
 
-    fn decode_label(s: &mut String, buf: &[u8]) -> Result<()> {
-        s.reserve_exact(buf.len());
-        for &c in buf {
-            if Self::is_valid_label_char(c) {
-                s.push(c as char)
-            } else {
-                bail!("bad label char");
+    fn encode(&self, buf: &mut Vec<u8>, mut compr: Option<&mut Compressor>) {
+        buf.reserve(self.0.len() + 1);
+        for s in self.labels_wire() {
+            if let Some(compr) = compr.as_deref_mut() {
+                if let Some(pos) = compr.get(s) {
+                    buf.put_u16(0xc000 | pos);
+                    return;
+                }
+                compr.insert(s, buf);
+            }
+            buf.put_slice(&s[..s[0] as usize + 1]);
+        }
+        buf.put_u8(0);
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_root() {
+            return f.write_char('.');
+        }
+        for (i, label) in self.labels().enumerate() {
+            if i > 0 {
+                f.write_char('.')?;
+            }
+            for &c in label {
+                match c {
+                    b'.' | b'\\' => write!(f, "\\{}", c as char)?,
+                    0x21..=0x7e => f.write_char(c as char)?,
+                    _ => write!(f, "\\{:03}", c)?,
+                }
             }
         }
         Ok(())
     }
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Self) -> bool {
+        // Length octets are below 64 so they are never affected by the case folding.
+        self.0.eq_ignore_ascii_case(&other.0)
+    }
+}
+
+impl Eq for Name {}
 
-    fn is_valid_label_char(c: u8) -> bool {
-        match c {
-            | b'a'..=b'z'
-            | b'A'..=b'Z'
-            | b'0'..=b'9'
-            | b'-'
-            => true,
-            _ => false,
+impl Hash for Name {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_usize(self.0.len());
+        for c in &self.0 {
+            state.write_u8(c.to_ascii_lowercase());
         }
     }
+}
 
-    fn encode(&self, buf: &mut Vec<u8>) {
-        buf.reserve(self.0.len() + 1);
-        let mut s = self.0.as_bytes();
+impl PartialOrd for Name {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Name {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let mut a = self.labels().rev();
+        let mut b = other.labels().rev();
         loop {
-            let i = s.iter().position(|&b| b == b'.').unwrap_or(s.len());
-            assert!(i > 0 && i <= 63);
-            buf.put_u8(i as u8);
-            buf.put_slice(&s[..i]);
-            if i == s.len() {
-                break;
+            match (a.next(), b.next()) {
+                (Some(a), Some(b)) => {
+                    let r = a.iter().map(u8::to_ascii_lowercase)
+                        .cmp(b.iter().map(u8::to_ascii_lowercase));
+                    if r != Ordering::Equal {
+                        return r;
+                    }
+                }
+                (a, b) => return a.is_some().cmp(&b.is_some()),
             }
-            s = &s[i + 1..];
         }
-        buf.put_u8(0);
     }
 }
 
-impl fmt::Display for Name {
+impl fmt::Debug for Name {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if self.0.is_empty() {
-            f.write_char('.')
-        } else {
-            f.write_str(&self.0)
-        }
+        f.debug_tuple("Name").field(&format_args!("{}", self)).finish()
     }
 }
 
@@ -172,32 +361,99 @@ pub struct ParseNameErr(());
 impl FromStr for Name {
     type Err = ParseNameErr;
 
+    /// Parses name in the presentation format. Supports `\.` and `\DDD` escapes. The trailing dot
+    /// is optional.
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         if s.is_empty() {
             return Err(ParseNameErr(()));
         }
         if s == "." {
-            return std::result::Result::Ok(Name(String::new()));
+            return std::result::Result::Ok(Name::default());
         }
-        let mut prev_is_sep = true;
-        for &c in s.as_bytes() {
-            if c == b'.' {
-                if prev_is_sep {
-                    return Err(ParseNameErr(()));
-                }
-                prev_is_sep = true;
-            } else if !Self::is_valid_label_char(c) {
+
+        fn end_label(r: &mut [u8], label_pos: usize) -> std::result::Result<(), ParseNameErr> {
+            let len = r.len() - label_pos - 1;
+            if len == 0 || len > Name::MAX_LABEL_LEN {
                 return Err(ParseNameErr(()));
-            } else {
-                prev_is_sep = false;
             }
+            r[label_pos] = len as u8;
+            std::result::Result::Ok(())
+        }
+
+        let mut r = vec![0];
+        let mut label_pos = 0;
+        let mut s = s.as_bytes();
+        while let Some((&c, rest)) = s.split_first() {
+            s = rest;
+            match c {
+                b'.' => {
+                    end_label(&mut r, label_pos)?;
+                    if s.is_empty() {
+                        break;
+                    }
+                    label_pos = r.len();
+                    r.push(0);
+                    continue;
+                }
+                b'\\' => {
+                    let c = if s.len() >= 3 && s[..3].iter().all(u8::is_ascii_digit) {
+                        let v = s[..3].iter().fold(0u32, |v, &d| v * 10 + (d - b'0') as u32);
+                        s = &s[3..];
+                        u8::try_from(v).map_err(|_| ParseNameErr(()))?
+                    } else {
+                        let (&c, rest) = s.split_first().ok_or(ParseNameErr(()))?;
+                        s = rest;
+                        c
+                    };
+                    r.push(c);
+                }
+                _ => r.push(c),
+            }
+            if s.is_empty() {
+                end_label(&mut r, label_pos)?;
+            }
+        }
+        if r.len() + 1 > Self::MAX_LEN {
+            return Err(ParseNameErr(()));
+        }
+        std::result::Result::Ok(Name(r.into()))
+    }
+}
+
+/// Name compression table (RFC 1035 4.1.4). Maps name suffixes already written into the packet
+/// to their offsets.
+struct Compressor {
+    pkt_start: usize,
+    names: HashMap<Vec<u8>, u16>,
+}
+
+impl Compressor {
+    /// Max offset that can be encoded in a compression pointer.
+    const MAX_POS: usize = 0x3fff;
+
+    fn new(pkt_start: usize) -> Self {
+        Self {
+            pkt_start,
+            names: HashMap::new(),
+        }
+    }
+
+    fn get(&self, name: &[u8]) -> Option<u16> {
+        self.names.get(name).copied()
+    }
+
+    fn insert(&mut self, name: &[u8], buf: &[u8]) {
+        let pos = buf.len() - self.pkt_start;
+        if pos <= Self::MAX_POS {
+            self.names.entry(name.to_owned()).or_insert(pos as u16);
         }
-        std::result::Result::Ok(Name(s.to_string()))
     }
 }
 
 const HEADER_LEN: u16 = 12;
-const MAX_HOPS: u32 = 10;
+
+/// Length of RR with the root name and empty data.
+const MIN_RR_LEN: usize = 11;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum PacketKind {
@@ -211,7 +467,7 @@ pub const OP_IQUERY: OpKind = 1;
 pub const OP_STATUS: OpKind = 2;
 pub const OP_UPDATE: OpKind = 5;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Packet {
     pub id: u16,
     pub kind: PacketKind,
@@ -220,11 +476,17 @@ pub struct Packet {
     pub truncated: bool,
     pub recursion_desired: bool,
     pub recursion_available: bool,
+    /// AD bit (RFC 4035 section 3.2.3).
+    pub authentic_data: bool,
+    /// CD bit (RFC 4035 section 3.2.2).
+    pub checking_disabled: bool,
     pub response_code: ResponseCode,
     pub question: Question,
     pub answers: Vec<ResourceRecord>,
     pub authorities: Vec<ResourceRecord>,
+    /// Additional RRs excluding the OPT pseudo-RR which is represented by `edns`.
     pub additional_rrs: Vec<ResourceRecord>,
+    pub edns: Option<Edns>,
 }
 
 impl Packet {
@@ -242,17 +504,20 @@ impl Packet {
             truncated: false,
             recursion_desired: false,
             recursion_available: false,
+            authentic_data: false,
+            checking_disabled: false,
             response_code: RCODE_NO_ERROR,
             question,
             answers: vec![],
             authorities: vec![],
             additional_rrs: vec![],
+            edns: None,
         }
     }
 
     pub fn decode(pkt: &[u8]) -> Result<Self> {
         if pkt.len() < HEADER_LEN as usize {
-            bail!("too short")
+            return Err(Error::TruncatedHeader);
         }
 
         let cursor = &mut &pkt[..];
@@ -270,39 +535,50 @@ impl Packet {
         let truncated = flags.get_bit(9);
         let recursion_desired = flags.get_bit(8);
         let recursion_available = flags.get_bit(7);
-        let response_code = flags.get_bits(0..4) as u8;
+        let authentic_data = flags.get_bit(5);
+        let checking_disabled = flags.get_bit(4);
+        let mut response_code = flags.get_bits(0..4);
 
         let question_count = cursor.get_u16();
-        if question_count == 0 {
-            bail!("empty question section");
+        // In practice no server supports multiple questions, so it's safer to reject them.
+        // This also rejects the QDCOUNT=0 queries carrying only a COOKIE option (RFC 7873 section
+        // 5.4). Server cookies aren't supported so such queries get FORMERR, which the RFC expects
+        // from servers that don't implement cookies.
+        if question_count != 1 {
+            return Err(Error::BadQuestionCount(question_count));
         }
         let answer_count = cursor.get_u16();
         let authority_count = cursor.get_u16();
         let additional_rr_count = cursor.get_u16();
 
         let question = Question::decode(pkt, cursor)?;
-        for _ in 1..question_count {
-            // TODO optimize
-            Question::decode(pkt, cursor)?;
-        }
 
-        let mut answers = Vec::with_capacity(answer_count as usize);
+        // Don't trust the counts for preallocation, the RRs may not be there.
+        let rr_capacity = |count: u16, cursor: &&[u8]| {
+            (count as usize).min(cursor.len() / MIN_RR_LEN)
+        };
+        let mut answers = Vec::with_capacity(rr_capacity(answer_count, cursor));
         for _ in 0..answer_count {
-            if let Some(rr) = ResourceRecord::decode(pkt, cursor)? {
-                answers.push(rr);
-            }
+            answers.push(ResourceRecord::decode(pkt, cursor)?);
         }
 
-        let mut authorities = Vec::with_capacity(authority_count as usize);
+        let mut authorities = Vec::with_capacity(rr_capacity(authority_count, cursor));
         for _ in 0..authority_count {
-            if let Some(rr) = ResourceRecord::decode(pkt, cursor)? {
-                authorities.push(rr);
-            }
+            authorities.push(ResourceRecord::decode(pkt, cursor)?);
         }
 
-        let mut additional_rrs = Vec::with_capacity(additional_rr_count as usize);
+        let mut additional_rrs = Vec::with_capacity(rr_capacity(additional_rr_count, cursor));
+        let mut edns = None;
         for _ in 0..additional_rr_count {
-            if let Some(rr) = ResourceRecord::decode(pkt, cursor)? {
+            let rr = ResourceRecord::decode(pkt, cursor)?;
+            if rr.kind == RRK_OPT {
+                if edns.is_some() {
+                    return Err(Error::BadOpt);
+                }
+                let (v, ext_response_code) = Edns::from_rr(rr)?;
+                response_code |= u16::from(ext_response_code) << 4;
+                edns = Some(v);
+            } else {
                 additional_rrs.push(rr);
             }
         }
@@ -315,16 +591,121 @@ impl Packet {
             truncated,
             recursion_desired,
             recursion_available,
+            authentic_data,
+            checking_disabled,
             response_code,
             question,
             answers,
             authorities,
             additional_rrs,
+            edns,
         })
     }
 
-    pub fn encode(&self, buf: &mut Vec<u8>) {
-        // TODO name compression
+    /// Appends a FORMERR response to the undecodable `query` to `buf`. The response echoes the ID,
+    /// opcode and RD flag of the query and has all sections empty. Returns `false` and leaves `buf`
+    /// unchanged if the query header can't be decoded or it's not a query.
+    pub fn encode_format_error(query: &[u8], buf: &mut Vec<u8>) -> bool {
+        if query.len() < HEADER_LEN as usize {
+            return false;
+        }
+        let cursor = &mut &query[..];
+        let id = cursor.get_u16();
+        let query_flags = cursor.get_u16();
+        if query_flags.get_bit(15) {
+            return false;
+        }
+
+        let mut flags = 0;
+        flags.set_bit(15, true);
+        flags.set_bits(11..15, query_flags.get_bits(11..15));
+        flags.set_bit(8, query_flags.get_bit(8));
+        flags.set_bits(0..4, RCODE_FORMAT_ERROR);
+
+        buf.reserve(HEADER_LEN as usize);
+        buf.put_u16(id);
+        buf.put_u16(flags);
+        buf.put_bytes(0, 8);
+        true
+    }
+
+    /// Appends the encoded packet to `buf`. On error `buf` is left unchanged.
+    pub fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        let start = buf.len();
+        let r = self.encode0(buf, None);
+        if r.is_err() {
+            buf.truncate(start);
+        }
+        r
+    }
+
+    /// Like `encode()` but if the encoded packet is longer than `max_len`, drops whole RRsets from
+    /// the end of the packet until it fits. If any RRs are dropped from the answer or authority
+    /// sections the truncated flag is set (RFC 2181 9). The OPT RR is never dropped. If even the
+    /// question and the OPT RR don't fit, only the header is left with TC=1 and zero counts.
+    pub fn encode_truncated(&self, buf: &mut Vec<u8>, max_len: usize) -> Result<()> {
+        let start = buf.len();
+        let mut ends = Vec::new();
+        let r = self.encode0(buf, Some(&mut ends));
+        if r.is_err() {
+            buf.truncate(start);
+            return r;
+        }
+        if buf.len() - start <= max_len {
+            return Ok(());
+        }
+
+        // Nothing after the RRs refers to them so the OPT RR can be moved to the cut point as is.
+        let opt_start = *ends.last().unwrap();
+        let opt_len = buf.len() - opt_start;
+        let rrs: Vec<_> = self.resource_records().collect();
+        let answer_count = self.answers.len();
+        let authority_count = self.authorities.len();
+        let section_ends = [answer_count, answer_count + authority_count];
+        // Number of RRs to keep.
+        let keep = (0..rrs.len()).rev()
+            .filter(|&i| i == 0 || section_ends.contains(&i) || !rrs[i - 1].same_rrset(rrs[i]))
+            .find(|&i| ends[i] + opt_len - start <= max_len);
+
+        let header = start..start + HEADER_LEN as usize;
+        let mut counts = [0; 4];
+        if let Some(keep) = keep {
+            buf.copy_within(opt_start.., ends[keep]);
+            buf.truncate(ends[keep] + opt_len);
+            counts = [
+                1,
+                keep.min(answer_count),
+                keep.saturating_sub(answer_count).min(authority_count),
+                keep.saturating_sub(section_ends[1]) + self.edns.iter().len(),
+            ];
+            if keep < section_ends[1] {
+                buf[header.start + 2].set_bit(1, true);
+            }
+        } else {
+            buf.truncate(header.end);
+            buf[header.start + 2].set_bit(1, true);
+        }
+        for (i, count) in counts.into_iter().enumerate() {
+            BE::write_u16(&mut buf[header.start + 4 + i * 2..], count as u16);
+        }
+        Ok(())
+    }
+
+    /// Max response length the sender of this query can accept over UDP (RFC 6891 6.2.5).
+    pub fn max_udp_payload_size(&self) -> usize {
+        self.edns.as_ref()
+            .map(|v| v.udp_payload_size)
+            .unwrap_or(0)
+            .max(MIN_UDP_PAYLOAD_SIZE) as usize
+    }
+
+    /// If `ends` is given, pushes there the end offsets of the question and each RR except OPT.
+    fn encode0(&self, buf: &mut Vec<u8>, mut ends: Option<&mut Vec<usize>>) -> Result<()> {
+        if self.response_code > 0xfff || self.response_code > 0xf && self.edns.is_none() {
+            return Err(Error::BadResponseCode(self.response_code));
+        }
+
+        let mut compr = Compressor::new(buf.len());
 
         buf.reserve(HEADER_LEN as usize);
         buf.put_u16(self.id);
@@ -336,24 +717,38 @@ impl Packet {
         flags.set_bit(9, self.truncated);
         flags.set_bit(8, self.recursion_desired);
         flags.set_bit(7, self.recursion_available);
-        flags.set_bits(0..4, self.response_code as u16);
+        flags.set_bit(5, self.authentic_data);
+        flags.set_bit(4, self.checking_disabled);
+        flags.set_bits(0..4, self.response_code.get_bits(0..4));
         buf.put_u16(flags);
 
+        let opt = self.edns.as_ref()
+            .map(|edns| edns.to_rr(self.response_code.get_bits(4..12) as u8))
+            .transpose()?;
+
+        fn count(len: usize) -> Result<u16> {
+            len.try_into().map_err(|_| Error::SectionTooLong)
+        }
+
         buf.put_u16(1);
-        buf.put_u16(self.answers.len().try_into().unwrap());
-        buf.put_u16(self.authorities.len().try_into().unwrap());
-        buf.put_u16(self.additional_rrs.len().try_into().unwrap());
+        buf.put_u16(count(self.answers.len())?);
+        buf.put_u16(count(self.authorities.len())?);
+        buf.put_u16(count(self.additional_rrs.len() + opt.iter().len())?);
 
-        self.question.encode(buf);
-        for a in &self.answers {
-            a.encode(buf);
+        self.question.encode(buf, &mut compr);
+        if let Some(ends) = &mut ends {
+            ends.push(buf.len());
         }
-        for a in &self.authorities {
-            a.encode(buf);
+        for rr in self.resource_records() {
+            rr.encode(buf, &mut compr)?;
+            if let Some(ends) = &mut ends {
+                ends.push(buf.len());
+            }
         }
-        for a in &self.additional_rrs {
-            a.encode(buf);
+        if let Some(opt) = &opt {
+            opt.encode(buf, &mut compr)?;
         }
+        Ok(())
     }
 
     pub fn to_response_with_code(&self, response_code: ResponseCode) -> Self {
@@ -363,6 +758,7 @@ impl Packet {
             self.op_kind,
             self.question.clone());
         r.response_code = response_code;
+        r.edns = self.edns.as_ref().map(|v| v.to_response());
         r
     }
 
@@ -387,8 +783,8 @@ pub struct Question {
 impl Question {
     fn decode<'a>(pkt: &'a [u8], cursor: &mut &'a [u8]) -> Result<Self> {
         let name = Name::decode(pkt, cursor)?;
-        let kind = cursor.read_u16::<BE>().map_err(|_| anyhow!("bad question"))?;
-        let class = cursor.read_u16::<BE>().map_err(|_| anyhow!("bad question"))?;
+        let kind = cursor.read_u16::<BE>().map_err(|_| Error::UnexpectedEnd)?;
+        let class = cursor.read_u16::<BE>().map_err(|_| Error::UnexpectedEnd)?;
         Ok(Self {
             name,
             kind,
@@ -396,14 +792,14 @@ impl Question {
         })
     }
 
-    fn encode(&self, buf: &mut Vec<u8>) {
-        self.name.encode(buf);
+    fn encode(&self, buf: &mut Vec<u8>, compr: &mut Compressor) {
+        self.name.encode(buf, Some(compr));
         buf.put_u16(self.kind);
         buf.put_u16(self.class);
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ResourceRecord {
     pub name: Name,
     pub kind: RRKind,
@@ -413,54 +809,63 @@ pub struct ResourceRecord {
 }
 
 impl ResourceRecord {
-    fn decode<'a>(pkt: &'a [u8], cursor: &mut &'a [u8]) -> Result<Option<Self>> {
+    fn decode<'a>(pkt: &'a [u8], cursor: &mut &'a [u8]) -> Result<Self> {
         let name = Name::decode(pkt, cursor)?;
-        let kind = cursor.read_u16::<BE>().map_err(|_| anyhow!("bad RR"))?;
-        let class = cursor.read_u16::<BE>().map_err(|_| anyhow!("bad RR"))?;
-        let ttl_secs = cursor.read_u32::<BE>().map_err(|_| anyhow!("bad RR"))?;
-        let data_len = cursor.read_u16::<BE>().map_err(|_| anyhow!("bad RR"))? as usize;
+        let kind = cursor.read_u16::<BE>().map_err(|_| Error::UnexpectedEnd)?;
+        let class = cursor.read_u16::<BE>().map_err(|_| Error::UnexpectedEnd)?;
+        let ttl_secs = cursor.read_u32::<BE>().map_err(|_| Error::UnexpectedEnd)?;
+        let data_len = cursor.read_u16::<BE>().map_err(|_| Error::UnexpectedEnd)? as usize;
         if data_len > cursor.len() {
-            bail!("bad RR")
+            return Err(Error::UnexpectedEnd);
         }
-        let data = match (kind, class) {
-            (RRK_A, RRC_IN) => {
-                let mut b = [0; 4];
-                cursor.read_exact(&mut b).map_err(|_| anyhow!("bad RR data"))?;
-                RRData::Ipv4Addr(b.into())
-            }
-            (RRK_AAAA, RRC_IN) => {
-                let mut b = [0; 16];
-                cursor.read_exact(&mut b).map_err(|_| anyhow!("bad RR data"))?;
-                RRData::Ipv6Addr(b.into())
-            }
-            (RRK_CNAME, RRC_IN) => RRData::Name(Name::decode(pkt, cursor)?),
-            (RRK_SOA, RRC_IN) => RRData::Soa(Soa::decode(pkt, cursor)?),
-            _ => {
-                cursor.advance(data_len);
-                trace!(%name, kind, class, ttl_secs, data_len, "skipping unknown RR data");
-                return Ok(None);
-            }
+
+        // RR data parsers are bound to exactly `data_len` bytes by cutting the packet at the end
+        // of RR data. Names can still point anywhere before it since pointers only go backwards.
+        let pkt = &pkt[..pkt.len() - cursor.len() + data_len];
+        let buf = &mut &cursor[..data_len];
+        cursor.advance(data_len);
+        let data = match RRData::decode(pkt, buf, kind, class) {
+            Ok(v) if buf.is_empty() => v,
+            Ok(_) | Err(Error::UnexpectedEnd) => return Err(Error::RRDataLenMismatch { kind }),
+            Err(err) => return Err(err),
         };
-        Ok(Some(Self {
+        Ok(Self {
             name,
             kind,
             class,
             ttl_secs,
             data,
-        }))
+        })
     }
 
-    fn encode(&self, buf: &mut Vec<u8>) {
-        self.name.encode(buf);
+    fn encode(&self, buf: &mut Vec<u8>, compr: &mut Compressor) -> Result<()> {
+        self.name.encode(buf, Some(compr));
         buf.put_u16(self.kind);
         buf.put_u16(self.class);
         buf.put_u32(self.ttl_secs);
-        let data_len_pos = buf.len();
-        buf.put_u16(0);
-        self.data.encode(buf);
-        let data_len = buf.len() - data_len_pos - 2;
-        BE::write_u16(&mut buf[data_len_pos..data_len_pos + 2],
-                      data_len.try_into().unwrap());
+        encode_with_len(buf, Error::RRDataTooLong { kind: self.kind },
+            |buf| self.data.encode(buf, compr))
+    }
+
+    /// Whether both RRs belong to the same RRset.
+    fn same_rrset(&self, other: &Self) -> bool {
+        self.name == other.name && self.kind == other.kind && self.class == other.class
+    }
+
+    /// Whether RR data of this kind can contain compressed names (RFC 3597 section 4).
+    fn may_have_compressed_names(kind: RRKind) -> bool {
+        matches!(kind,
+            | RRK_NS
+            | RRK_MD
+            | RRK_MF
+            | RRK_CNAME
+            | RRK_SOA
+            | RRK_MB
+            | RRK_MG
+            | RRK_MR
+            | RRK_PTR
+            | RRK_MINFO
+            | RRK_MX)
     }
 
     pub fn ttl(&self) -> Duration {
@@ -470,20 +875,81 @@ impl ResourceRecord {
 
 #[derive(Clone, Debug, EnumAsInner, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub enum RRData {
+    /// CNAME target.
     Name(Name),
+    Ns(Name),
+    Ptr(Name),
     Ipv4Addr(Ipv4Addr),
     Ipv6Addr(Ipv6Addr),
     Soa(Soa),
+    Mx(Mx),
+    /// List of character-strings.
+    Txt(Vec<Vec<u8>>),
+    Srv(Srv),
+    /// SVCB or HTTPS.
+    Svcb(Svcb),
+    /// Opaque RR data of a kind that's not known to the codec (RFC 3597).
+    Unknown(Vec<u8>),
 }
 
 impl RRData {
-    fn encode(&self, buf: &mut Vec<u8>) {
+    /// Decodes RR data from `data` which must be consumed entirely.
+    fn decode<'a>(pkt: &'a [u8], data: &mut &'a [u8], kind: RRKind, class: RRClass)
+        -> Result<Self>
+    {
+        Ok(match (kind, class) {
+            (RRK_A, RRC_IN) => {
+                let mut b = [0; 4];
+                data.read_exact(&mut b).map_err(|_| Error::UnexpectedEnd)?;
+                Self::Ipv4Addr(b.into())
+            }
+            (RRK_AAAA, RRC_IN) => {
+                let mut b = [0; 16];
+                data.read_exact(&mut b).map_err(|_| Error::UnexpectedEnd)?;
+                Self::Ipv6Addr(b.into())
+            }
+            (RRK_CNAME, RRC_IN) => Self::Name(Name::decode(pkt, data)?),
+            (RRK_NS, RRC_IN) => Self::Ns(Name::decode(pkt, data)?),
+            (RRK_PTR, RRC_IN) => Self::Ptr(Name::decode(pkt, data)?),
+            (RRK_SOA, RRC_IN) => Self::Soa(Soa::decode(pkt, data)?),
+            (RRK_MX, RRC_IN) => Self::Mx(Mx::decode(pkt, data)?),
+            (RRK_TXT, RRC_IN) => {
+                let r = decode_txt(data).ok_or(Error::BadRRData { kind })?;
+                data.advance(data.len());
+                Self::Txt(r)
+            }
+            (RRK_SRV, RRC_IN) => Self::Srv(Srv::decode(pkt, data)?),
+            | (RRK_SVCB, RRC_IN)
+            | (RRK_HTTPS, RRC_IN)
+            => Self::Svcb(Svcb::decode(pkt, data, kind)?),
+            // Can't pass such RR data through as is because the compression pointers are only
+            // valid within the original packet.
+            _ if ResourceRecord::may_have_compressed_names(kind) =>
+                Self::Unknown(decompress(pkt, data, kind)?),
+            _ => {
+                let r = data.to_vec();
+                data.advance(data.len());
+                Self::Unknown(r)
+            }
+        })
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>, compr: &mut Compressor) -> Result<()> {
         match self {
-            Self::Name(v) => v.encode(buf),
+            | Self::Name(v)
+            | Self::Ns(v)
+            | Self::Ptr(v)
+            => v.encode(buf, Some(compr)),
             &Self::Ipv4Addr(v) => buf.put(&v.octets()[..]),
             Self::Ipv6Addr(v) => buf.put(&v.octets()[..]),
-            Self::Soa(v) => v.encode(buf),
+            Self::Soa(v) => v.encode(buf, compr),
+            Self::Mx(v) => v.encode(buf, compr),
+            Self::Txt(v) => encode_txt(v, buf)?,
+            Self::Srv(v) => v.encode(buf),
+            Self::Svcb(v) => v.encode(buf)?,
+            Self::Unknown(v) => buf.put_slice(v),
         }
+        Ok(())
     }
 }
 
@@ -502,11 +968,11 @@ impl Soa {
     fn decode<'a>(pkt: &'a [u8], cursor: &mut &'a [u8]) -> Result<Self> {
         let primary_name = Name::decode(pkt, cursor)?;
         let responsible_name = Name::decode(pkt, cursor)?;
-        let serial = cursor.read_u32::<BE>().map_err(|_| anyhow!("bad SOA"))?;
-        let refresh_secs = cursor.read_u32::<BE>().map_err(|_| anyhow!("bad SOA"))?;
-        let retry_secs = cursor.read_u32::<BE>().map_err(|_| anyhow!("bad SOA"))?;
-        let expire_secs = cursor.read_u32::<BE>().map_err(|_| anyhow!("bad SOA"))?;
-        let min_ttl_secs = cursor.read_u32::<BE>().map_err(|_| anyhow!("bad SOA"))?;
+        let serial = cursor.read_u32::<BE>().map_err(|_| Error::UnexpectedEnd)?;
+        let refresh_secs = cursor.read_u32::<BE>().map_err(|_| Error::UnexpectedEnd)?;
+        let retry_secs = cursor.read_u32::<BE>().map_err(|_| Error::UnexpectedEnd)?;
+        let expire_secs = cursor.read_u32::<BE>().map_err(|_| Error::UnexpectedEnd)?;
+        let min_ttl_secs = cursor.read_u32::<BE>().map_err(|_| Error::UnexpectedEnd)?;
         Ok(Self {
             primary_name,
             responsible_name,
@@ -518,13 +984,1291 @@ impl Soa {
         })
     }
 
-    fn encode(&self, buf: &mut Vec<u8>) {
-        self.primary_name.encode(buf);
-        self.responsible_name.encode(buf);
+    fn encode(&self, buf: &mut Vec<u8>, compr: &mut Compressor) {
+        self.primary_name.encode(buf, Some(compr));
+        self.responsible_name.encode(buf, Some(compr));
         buf.put_u32(self.serial);
         buf.put_u32(self.refresh_secs);
         buf.put_u32(self.retry_secs);
         buf.put_u32(self.expire_secs);
         buf.put_u32(self.min_ttl_secs);
     }
-}
\ No newline at end of file
+}
+
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Mx {
+    pub preference: u16,
+    pub exchange: Name,
+}
+
+impl Mx {
+    fn decode<'a>(pkt: &'a [u8], cursor: &mut &'a [u8]) -> Result<Self> {
+        let preference = cursor.read_u16::<BE>().map_err(|_| Error::UnexpectedEnd)?;
+        let exchange = Name::decode(pkt, cursor)?;
+        Ok(Self {
+            preference,
+            exchange,
+        })
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>, compr: &mut Compressor) {
+        buf.put_u16(self.preference);
+        self.exchange.encode(buf, Some(compr));
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Srv {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: Name,
+}
+
+impl Srv {
+    fn decode<'a>(pkt: &'a [u8], cursor: &mut &'a [u8]) -> Result<Self> {
+        let priority = cursor.read_u16::<BE>().map_err(|_| Error::UnexpectedEnd)?;
+        let weight = cursor.read_u16::<BE>().map_err(|_| Error::UnexpectedEnd)?;
+        let port = cursor.read_u16::<BE>().map_err(|_| Error::UnexpectedEnd)?;
+        let target = Name::decode(pkt, cursor)?;
+        Ok(Self {
+            priority,
+            weight,
+            port,
+            target,
+        })
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_u16(self.priority);
+        buf.put_u16(self.weight);
+        buf.put_u16(self.port);
+        // Target must not be compressed (RFC 2782).
+        self.target.encode(buf, None);
+    }
+}
+
+/// SVCB and HTTPS RR data (RFC 9460).
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Svcb {
+    /// Zero means alias mode.
+    pub priority: u16,
+    pub target: Name,
+    /// Service parameters. Encoded in the ascending key order regardless of the order here.
+    pub params: Vec<SvcParam>,
+}
+
+impl Svcb {
+    /// Decodes SVCB from `cursor` which must contain exactly the RR data.
+    fn decode<'a>(pkt: &'a [u8], cursor: &mut &'a [u8], kind: RRKind) -> Result<Self> {
+        let priority = cursor.read_u16::<BE>().map_err(|_| Error::UnexpectedEnd)?;
+        let target = Name::decode(pkt, cursor)?;
+
+        let mut data = *cursor;
+        cursor.advance(data.len());
+
+        let mut params = Vec::new();
+        let mut prev_key = None;
+        while !data.is_empty() {
+            let key = data.read_u16::<BE>().map_err(|_| Error::BadRRData { kind })?;
+            // Keys must be in strictly increasing order.
+            if prev_key.map(|k| k >= key).unwrap_or(false) {
+                return Err(Error::BadSvcParam(key));
+            }
+            prev_key = Some(key);
+            let len = data.read_u16::<BE>().map_err(|_| Error::BadSvcParam(key))? as usize;
+            if len > data.len() {
+                return Err(Error::BadSvcParam(key));
+            }
+            params.push(SvcParam::decode(key, &data[..len]).ok_or(Error::BadSvcParam(key))?);
+            data.advance(len);
+        }
+
+        Ok(Self {
+            priority,
+            target,
+            params,
+        })
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.put_u16(self.priority);
+        // Target must not be compressed (RFC 9460 section 2.2).
+        self.target.encode(buf, None);
+
+        let mut params: Vec<_> = self.params.iter().collect();
+        params.sort_by_key(|p| p.key());
+        // Keys must be unique (RFC 9460 section 2.2).
+        if let Some(w) = params.windows(2).find(|w| w[0].key() == w[1].key()) {
+            return Err(Error::BadSvcParam(w[0].key()));
+        }
+        for param in params {
+            buf.put_u16(param.key());
+            encode_with_len(buf, Error::BadSvcParam(param.key()), |buf| param.encode_value(buf))?;
+        }
+        Ok(())
+    }
+
+    pub fn param(&self, key: SvcParamKey) -> Option<&SvcParam> {
+        self.params.iter().find(|p| p.key() == key)
+    }
+
+    pub fn param_mut(&mut self, key: SvcParamKey) -> Option<&mut SvcParam> {
+        self.params.iter_mut().find(|p| p.key() == key)
+    }
+
+    pub fn remove_param(&mut self, key: SvcParamKey) -> Option<SvcParam> {
+        let i = self.params.iter().position(|p| p.key() == key)?;
+        Some(self.params.remove(i))
+    }
+}
+
+pub type SvcParamKey = u16;
+pub const SPK_MANDATORY: SvcParamKey = 0;
+pub const SPK_ALPN: SvcParamKey = 1;
+pub const SPK_NO_DEFAULT_ALPN: SvcParamKey = 2;
+pub const SPK_PORT: SvcParamKey = 3;
+pub const SPK_IPV4_HINT: SvcParamKey = 4;
+pub const SPK_ECH: SvcParamKey = 5;
+pub const SPK_IPV6_HINT: SvcParamKey = 6;
+
+#[derive(Clone, Debug, EnumAsInner, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum SvcParam {
+    Mandatory(Vec<SvcParamKey>),
+    Alpn(Vec<Vec<u8>>),
+    NoDefaultAlpn,
+    Port(u16),
+    Ipv4Hint(Vec<Ipv4Addr>),
+    /// ECHConfigList.
+    Ech(Vec<u8>),
+    Ipv6Hint(Vec<Ipv6Addr>),
+    Unknown(SvcParamKey, Vec<u8>),
+}
+
+impl SvcParam {
+    pub fn key(&self) -> SvcParamKey {
+        match self {
+            Self::Mandatory(_) => SPK_MANDATORY,
+            Self::Alpn(_) => SPK_ALPN,
+            Self::NoDefaultAlpn => SPK_NO_DEFAULT_ALPN,
+            Self::Port(_) => SPK_PORT,
+            Self::Ipv4Hint(_) => SPK_IPV4_HINT,
+            Self::Ech(_) => SPK_ECH,
+            Self::Ipv6Hint(_) => SPK_IPV6_HINT,
+            &Self::Unknown(key, _) => key,
+        }
+    }
+
+    fn decode(key: SvcParamKey, mut data: &[u8]) -> Option<Self> {
+        Some(match key {
+            SPK_MANDATORY => {
+                if data.is_empty() || !data.len().is_multiple_of(2) {
+                    return None;
+                }
+                Self::Mandatory(data.chunks_exact(2).map(BE::read_u16).collect())
+            }
+            SPK_ALPN => {
+                let mut ids = Vec::new();
+                while !data.is_empty() {
+                    let len = data.get_u8() as usize;
+                    if len == 0 || len > data.len() {
+                        return None;
+                    }
+                    ids.push(data[..len].to_vec());
+                    data.advance(len);
+                }
+                if ids.is_empty() {
+                    return None;
+                }
+                Self::Alpn(ids)
+            }
+            SPK_NO_DEFAULT_ALPN => {
+                if !data.is_empty() {
+                    return None;
+                }
+                Self::NoDefaultAlpn
+            }
+            SPK_PORT => {
+                if data.len() != 2 {
+                    return None;
+                }
+                Self::Port(BE::read_u16(data))
+            }
+            SPK_IPV4_HINT => {
+                if data.is_empty() || !data.len().is_multiple_of(4) {
+                    return None;
+                }
+                Self::Ipv4Hint(data.chunks_exact(4)
+                    .map(|b| Ipv4Addr::from(<[u8; 4]>::try_from(b).unwrap()))
+                    .collect())
+            }
+            SPK_ECH => Self::Ech(data.to_vec()),
+            SPK_IPV6_HINT => {
+                if data.is_empty() || !data.len().is_multiple_of(16) {
+                    return None;
+                }
+                Self::Ipv6Hint(data.chunks_exact(16)
+                    .map(|b| Ipv6Addr::from(<[u8; 16]>::try_from(b).unwrap()))
+                    .collect())
+            }
+            _ => Self::Unknown(key, data.to_vec()),
+        })
+    }
+
+    fn encode_value(&self, buf: &mut Vec<u8>) -> Result<()> {
+        match self {
+            Self::Mandatory(v) => {
+                for &key in v {
+                    buf.put_u16(key);
+                }
+            }
+            Self::Alpn(v) => {
+                for id in v {
+                    if id.is_empty() || id.len() > 255 {
+                        return Err(Error::BadSvcParam(SPK_ALPN));
+                    }
+                    buf.put_u8(id.len() as u8);
+                    buf.put_slice(id);
+                }
+            }
+            Self::NoDefaultAlpn => {}
+            &Self::Port(v) => buf.put_u16(v),
+            Self::Ipv4Hint(v) => {
+                for addr in v {
+                    buf.put_slice(&addr.octets());
+                }
+            }
+            Self::Ech(v) => buf.put_slice(v),
+            Self::Ipv6Hint(v) => {
+                for addr in v {
+                    buf.put_slice(&addr.octets());
+                }
+            }
+            Self::Unknown(_, v) => buf.put_slice(v),
+        }
+        Ok(())
+    }
+}
+
+/// Max UDP payload size for clients that don't support EDNS (RFC 1035 4.2.1).
+pub const MIN_UDP_PAYLOAD_SIZE: u16 = 512;
+
+/// UDP payload size advertised in responses.
+pub const DEFAULT_EDNS_UDP_PAYLOAD_SIZE: u16 = 1232;
+
+/// EDNS data carried in the OPT pseudo-RR (RFC 6891). The extended response code bits are merged
+/// into `Packet::response_code`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Default for Edns {
+    fn default() -> Self {
+        Self {
+            udp_payload_size: DEFAULT_EDNS_UDP_PAYLOAD_SIZE,
+            version: 0,
+            dnssec_ok: false,
+            options: vec![],
+        }
+    }
+}
+
+impl Edns {
+    /// Returns EDNS data to put into a response to the query with this EDNS data.
+    pub fn to_response(&self) -> Self {
+        Self {
+            dnssec_ok: self.dnssec_ok,
+            ..Default::default()
+        }
+    }
+
+    pub fn option(&self, code: EdnsOptionCode) -> Option<&EdnsOption> {
+        self.options.iter().find(|o| o.code() == code)
+    }
+
+    /// Returns the EDNS data and the upper 8 bits of the extended response code.
+    fn from_rr(rr: ResourceRecord) -> Result<(Self, u8)> {
+        if !rr.name.is_root() {
+            return Err(Error::BadOpt);
+        }
+        let mut data = &rr.data.as_unknown().ok_or(Error::BadOpt)?[..];
+        let mut options = Vec::new();
+        while !data.is_empty() {
+            let code = data.read_u16::<BE>().map_err(|_| Error::BadOpt)?;
+            let len = data.read_u16::<BE>().map_err(|_| Error::BadEdnsOption(code))? as usize;
+            if len > data.len() {
+                return Err(Error::BadEdnsOption(code));
+            }
+            options.push(EdnsOption::decode(code, &data[..len])
+                .ok_or(Error::BadEdnsOption(code))?);
+            data.advance(len);
+        }
+        Ok((Self {
+            udp_payload_size: rr.class,
+            version: rr.ttl_secs.get_bits(16..24) as u8,
+            dnssec_ok: rr.ttl_secs.get_bit(15),
+            options,
+        }, rr.ttl_secs.get_bits(24..32) as u8))
+    }
+
+    fn to_rr(&self, ext_response_code: u8) -> Result<ResourceRecord> {
+        let mut ttl_secs = 0;
+        ttl_secs.set_bits(24..32, ext_response_code as u32);
+        ttl_secs.set_bits(16..24, self.version as u32);
+        ttl_secs.set_bit(15, self.dnssec_ok);
+
+        let mut data = Vec::new();
+        for option in &self.options {
+            data.put_u16(option.code());
+            encode_with_len(&mut data, Error::BadEdnsOption(option.code()),
+                |buf| option.encode_value(buf))?;
+        }
+
+        Ok(ResourceRecord {
+            name: Name::default(),
+            kind: RRK_OPT,
+            class: self.udp_payload_size,
+            ttl_secs,
+            data: RRData::Unknown(data),
+        })
+    }
+}
+
+pub type EdnsOptionCode = u16;
+pub const EOC_CLIENT_SUBNET: EdnsOptionCode = 8;
+pub const EOC_COOKIE: EdnsOptionCode = 10;
+pub const EOC_TCP_KEEPALIVE: EdnsOptionCode = 11;
+pub const EOC_PADDING: EdnsOptionCode = 12;
+
+#[derive(Clone, Debug, EnumAsInner, Eq, PartialEq)]
+pub enum EdnsOption {
+    ClientSubnet(ClientSubnet),
+    Cookie {
+        client: [u8; 8],
+        /// Empty or 8 to 32 bytes.
+        server: Vec<u8>,
+    },
+    /// Idle timeout in units of 100 milliseconds. Must be `None` in queries.
+    TcpKeepalive(Option<u16>),
+    /// Length of the padding.
+    Padding(u16),
+    Unknown(EdnsOptionCode, Vec<u8>),
+}
+
+impl EdnsOption {
+    pub fn code(&self) -> EdnsOptionCode {
+        match self {
+            Self::ClientSubnet(_) => EOC_CLIENT_SUBNET,
+            Self::Cookie { .. } => EOC_COOKIE,
+            Self::TcpKeepalive(_) => EOC_TCP_KEEPALIVE,
+            Self::Padding(_) => EOC_PADDING,
+            &Self::Unknown(code, _) => code,
+        }
+    }
+
+    fn decode(code: EdnsOptionCode, data: &[u8]) -> Option<Self> {
+        Some(match code {
+            EOC_CLIENT_SUBNET => Self::ClientSubnet(ClientSubnet::decode(data)?),
+            EOC_COOKIE => {
+                if data.len() != 8 && !(16..=40).contains(&data.len()) {
+                    return None;
+                }
+                Self::Cookie {
+                    client: data[..8].try_into().unwrap(),
+                    server: data[8..].to_vec(),
+                }
+            }
+            EOC_TCP_KEEPALIVE => Self::TcpKeepalive(match data.len() {
+                0 => None,
+                2 => Some(BE::read_u16(data)),
+                _ => return None,
+            }),
+            EOC_PADDING => Self::Padding(data.len() as u16),
+            _ => Self::Unknown(code, data.to_vec()),
+        })
+    }
+
+    fn encode_value(&self, buf: &mut Vec<u8>) -> Result<()> {
+        match self {
+            Self::ClientSubnet(v) => v.encode(buf)?,
+            Self::Cookie { client, server } => {
+                buf.put_slice(client);
+                buf.put_slice(server);
+            }
+            &Self::TcpKeepalive(v) => if let Some(v) = v {
+                buf.put_u16(v);
+            }
+            &Self::Padding(len) => buf.put_bytes(0, len as usize),
+            Self::Unknown(_, v) => buf.put_slice(v),
+        }
+        Ok(())
+    }
+}
+
+/// EDNS Client Subnet option data (RFC 7871).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ClientSubnet {
+    pub source_prefix_len: u8,
+    pub scope_prefix_len: u8,
+    /// Address with the bits beyond `source_prefix_len` set to zero.
+    pub addr: IpAddr,
+}
+
+impl ClientSubnet {
+    const FAMILY_IPV4: u16 = 1;
+    const FAMILY_IPV6: u16 = 2;
+
+    fn decode(mut data: &[u8]) -> Option<Self> {
+        let family = data.read_u16::<BE>().ok()?;
+        let source_prefix_len = data.read_u8().ok()?;
+        let scope_prefix_len = data.read_u8().ok()?;
+        let addr_len = match family {
+            Self::FAMILY_IPV4 => 4,
+            Self::FAMILY_IPV6 => 16,
+            _ => return None,
+        };
+        if source_prefix_len as usize > addr_len * 8
+            || data.len() != (source_prefix_len as usize).div_ceil(8)
+        {
+            return None;
+        }
+        // Bits beyond the prefix must be zero (RFC 7871 section 6).
+        if let Some(&last) = data.last() {
+            if last & !Self::last_octet_mask(source_prefix_len) != 0 {
+                return None;
+            }
+        }
+        let mut b = [0; 16];
+        b[..data.len()].copy_from_slice(data);
+        let addr = if family == Self::FAMILY_IPV4 {
+            IpAddr::V4(<[u8; 4]>::try_from(&b[..4]).unwrap().into())
+        } else {
+            IpAddr::V6(b.into())
+        };
+        Some(Self {
+            source_prefix_len,
+            scope_prefix_len,
+            addr,
+        })
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) -> Result<()> {
+        let (family, mut octets) = match self.addr {
+            IpAddr::V4(v) => (Self::FAMILY_IPV4, v.octets().to_vec()),
+            IpAddr::V6(v) => (Self::FAMILY_IPV6, v.octets().to_vec()),
+        };
+        let len = (self.source_prefix_len as usize).div_ceil(8);
+        if len > octets.len() {
+            return Err(Error::BadEdnsOption(EOC_CLIENT_SUBNET));
+        }
+        if len > 0 {
+            octets[len - 1] &= Self::last_octet_mask(self.source_prefix_len);
+        }
+        buf.put_u16(family);
+        buf.put_u8(self.source_prefix_len);
+        buf.put_u8(self.scope_prefix_len);
+        buf.put_slice(&octets[..len]);
+        Ok(())
+    }
+
+    /// Returns the mask of the prefix bits in the last address octet that holds any of them.
+    fn last_octet_mask(source_prefix_len: u8) -> u8 {
+        match source_prefix_len % 8 {
+            0 => 0xff,
+            v => 0xff << (8 - v),
+        }
+    }
+}
+
+/// Returns RR data of a `kind` that may have compressed names with the names decompressed.
+fn decompress<'a>(pkt: &'a [u8], data: &mut &'a [u8], kind: RRKind) -> Result<Vec<u8>> {
+    let mut r = Vec::with_capacity(data.len());
+    let (prefix_len, name_count, suffix_len) = match kind {
+        RRK_SOA => (0, 2, 20),
+        RRK_MINFO => (0, 2, 0),
+        RRK_MX => (2, 1, 0),
+        _ => (0, 1, 0),
+    };
+    let read = |data: &mut &[u8], len, r: &mut Vec<u8>| {
+        r.extend_from_slice(data.get(..len).ok_or(Error::UnexpectedEnd)?);
+        data.advance(len);
+        Ok(())
+    };
+    read(data, prefix_len, &mut r)?;
+    for _ in 0..name_count {
+        Name::decode(pkt, data)?.encode(&mut r, None);
+    }
+    read(data, suffix_len, &mut r)?;
+    Ok(r)
+}
+
+/// Decodes TXT RR data which must hold at least one character-string.
+fn decode_txt(mut data: &[u8]) -> Option<Vec<Vec<u8>>> {
+    if data.is_empty() {
+        return None;
+    }
+    let mut r = Vec::new();
+    while !data.is_empty() {
+        let len = data.get_u8() as usize;
+        if len > data.len() {
+            return None;
+        }
+        r.push(data[..len].to_vec());
+        data.advance(len);
+    }
+    Some(r)
+}
+
+fn encode_txt(strings: &[Vec<u8>], buf: &mut Vec<u8>) -> Result<()> {
+    if strings.is_empty() {
+        return Err(Error::BadRRData { kind: RRK_TXT });
+    }
+    for s in strings {
+        if s.len() > 255 {
+            return Err(Error::CharStringTooLong);
+        }
+        buf.put_u8(s.len() as u8);
+        buf.put_slice(s);
+    }
+    Ok(())
+}
+
+/// Writes the data produced by `f` prefixed with its `u16` length. Returns `len_err` if the data
+/// is too long.
+fn encode_with_len(
+    buf: &mut Vec<u8>,
+    len_err: Error,
+    f: impl FnOnce(&mut Vec<u8>) -> Result<()>,
+) -> Result<()> {
+    let len_pos = buf.len();
+    buf.put_u16(0);
+    f(buf)?;
+    let len = u16::try_from(buf.len() - len_pos - 2).map_err(|_| len_err)?;
+    BE::write_u16(&mut buf[len_pos..len_pos + 2], len);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rr(name: &str, kind: RRKind, data: RRData) -> ResourceRecord {
+        ResourceRecord {
+            name: name.parse().unwrap(),
+            kind,
+            class: RRC_IN,
+            ttl_secs: 300,
+            data,
+        }
+    }
+
+    fn name(s: &str) -> Name {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn encode_compressed() {
+        let mut pkt = Packet::new(
+            0x1234,
+            PacketKind::Response,
+            OP_QUERY,
+            Question {
+                name: name("www.example.com"),
+                kind: RRK_A,
+                class: RRC_IN,
+            });
+        pkt.answers = vec![
+            rr("www.example.com", RRK_CNAME, RRData::Name(name("cdn.example.com"))),
+            rr("cdn.example.com", RRK_CNAME, RRData::Name(name("edge.cdn.example.net"))),
+            rr("edge.cdn.example.net", RRK_A, RRData::Ipv4Addr(Ipv4Addr::new(1, 2, 3, 4))),
+        ];
+        pkt.authorities = vec![
+            rr("example.net", RRK_SOA, RRData::Soa(Soa {
+                primary_name: name("ns1.example.net"),
+                responsible_name: name("hostmaster.example.net"),
+                serial: 1,
+                refresh_secs: 2,
+                retry_secs: 3,
+                expire_secs: 4,
+                min_ttl_secs: 5,
+            })),
+        ];
+
+        let mut buf = Vec::new();
+        pkt.encode(&mut buf).unwrap();
+        // 246 bytes without compression.
+        assert_eq!(buf.len(), 152);
+
+        assert_eq!(Packet::decode(&buf).unwrap(), pkt);
+    }
+
+    #[test]
+    fn encode_root_name() {
+        let pkt = Packet::new(1, PacketKind::Query, OP_QUERY, Question {
+            name: name("."),
+            kind: RRK_SOA,
+            class: RRC_IN,
+        });
+        let mut buf = Vec::new();
+        pkt.encode(&mut buf).unwrap();
+        assert_eq!(&buf[HEADER_LEN as usize..], &[0, 0, 6, 0, 1]);
+        assert_eq!(Packet::decode(&buf).unwrap(), pkt);
+    }
+
+    #[test]
+    fn dnssec_flags() {
+        let mut pkt = Packet::new(1, PacketKind::Query, OP_QUERY, Question {
+            name: name("example.com"),
+            kind: RRK_A,
+            class: RRC_IN,
+        });
+        pkt.recursion_desired = true;
+        pkt.checking_disabled = true;
+        let mut buf = Vec::new();
+        pkt.encode(&mut buf).unwrap();
+        assert_eq!(&buf[2..4], &[0x01, 0x10]);
+        assert_eq!(Packet::decode(&buf).unwrap(), pkt);
+
+        pkt.checking_disabled = false;
+        pkt.authentic_data = true;
+        buf.clear();
+        pkt.encode(&mut buf).unwrap();
+        assert_eq!(&buf[2..4], &[0x01, 0x20]);
+        assert_eq!(Packet::decode(&buf).unwrap(), pkt);
+    }
+
+    #[test]
+    fn unknown_rr_data_passthrough() {
+        let pkt = [
+            0, 1, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0,
+            3, b'f', b'o', b'o', 0, 0, 99, 0, 1,
+            // Type 99 (SPF)
+            0xc0, 12, 0, 99, 0, 1, 0, 0, 0, 60, 0, 6, 5, b'h', b'e', b'l', b'l', b'o',
+            // Type 65535 with empty data.
+            0xc0, 12, 0xff, 0xff, 0, 1, 0, 0, 0, 60, 0, 0,
+        ];
+        let decoded = Packet::decode(&pkt).unwrap();
+        assert_eq!(decoded.answers[0].data, RRData::Unknown(b"\x05hello".to_vec()));
+        assert_eq!(decoded.answers[1].data, RRData::Unknown(vec![]));
+
+        let mut buf = Vec::new();
+        decoded.encode(&mut buf).unwrap();
+        assert_eq!(&buf[..], &pkt[..]);
+    }
+
+    #[test]
+    fn compressed_rr_data_passthrough() {
+        let mut pkt = vec![
+            0, 1, 0x81, 0x80, 0, 1, 0, 3, 0, 0, 0, 0,
+            3, b'f', b'o', b'o', 0, 0, 255, 0, 255,
+            // MINFO
+            0xc0, 12, 0, 14, 0, 1, 0, 0, 0, 60, 0, 8, 3, b'b', b'a', b'r', 0xc0, 12, 0xc0, 12,
+            // PTR in CH class.
+            0xc0, 12, 0, 12, 0, 3, 0, 0, 0, 60, 0, 2, 0xc0, 12,
+            // SOA in CH class.
+            0xc0, 12, 0, 6, 0, 3, 0, 0, 0, 60, 0, 24, 0xc0, 12, 0xc0, 12,
+        ];
+        pkt.extend(1..=20);
+        let decoded = Packet::decode(&pkt).unwrap();
+        assert_eq!(decoded.answers[0].data,
+            RRData::Unknown(b"\x03bar\x03foo\0\x03foo\0".to_vec()));
+        assert_eq!(decoded.answers[1].class, RRC_CH);
+        assert_eq!(decoded.answers[1].data, RRData::Unknown(b"\x03foo\0".to_vec()));
+        let mut soa = b"\x03foo\0\x03foo\0".to_vec();
+        soa.extend(1..=20);
+        assert_eq!(decoded.answers[2].data, RRData::Unknown(soa));
+
+        let mut buf = Vec::new();
+        decoded.encode(&mut buf).unwrap();
+        assert_eq!(Packet::decode(&buf).unwrap(), decoded);
+
+        // SOA fixed fields are cut short.
+        pkt[66] = 23;
+        pkt.pop();
+        assert_eq!(Packet::decode(&pkt), Err(Error::RRDataLenMismatch { kind: RRK_SOA }));
+    }
+
+    #[test]
+    fn typed_rr_data() {
+        let mut pkt = Packet::new(7, PacketKind::Response, OP_QUERY, Question {
+            name: name("example.com"),
+            kind: RRKQ_ALL,
+            class: RRC_IN,
+        });
+        pkt.answers = vec![
+            rr("example.com", RRK_NS, RRData::Ns(name("ns1.example.com"))),
+            rr("example.com", RRK_MX, RRData::Mx(Mx {
+                preference: 10,
+                exchange: name("mail.example.com"),
+            })),
+            rr("example.com", RRK_TXT, RRData::Txt(vec![
+                b"v=spf1 -all".to_vec(),
+                vec![],
+                vec![b'x'; 255],
+            ])),
+            rr("4.3.2.1.in-addr.arpa", RRK_PTR, RRData::Ptr(name("example.com"))),
+            rr("sip.example.com", RRK_SRV, RRData::Srv(Srv {
+                priority: 1,
+                weight: 2,
+                port: 5060,
+                target: name("sip.example.com"),
+            })),
+        ];
+
+        let mut buf = Vec::new();
+        pkt.encode(&mut buf).unwrap();
+        assert_eq!(Packet::decode(&buf).unwrap(), pkt);
+
+        // SRV target is not compressed.
+        assert!(buf.ends_with(b"\x03sip\x07example\x03com\x00"));
+    }
+
+    #[test]
+    fn svcb_rr_data() {
+        // RFC 9460 appendix D.2, figure 9 with the target name changed.
+        let rdata = b"\x00\x01\x03foo\x07example\x03com\x00\
+            \x00\x01\x00\x06\x02h2\x02h3\
+            \x00\x03\x00\x02\x00\x35\
+            \x00\x04\x00\x08\xc0\x00\x02\x01\xc0\x00\x02\x02\
+            \x00\x05\x00\x03\x01\x02\x03\
+            \x00\x09\x00\x01\xff";
+        let mut pkt = vec![
+            0, 1, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0,
+            3, b'f', b'o', b'o', 0, 0, 65, 0, 1,
+            0xc0, 12, 0, 65, 0, 1, 0, 0, 0, 60, 0, rdata.len() as u8,
+        ];
+        pkt.extend_from_slice(rdata);
+
+        let mut decoded = Packet::decode(&pkt).unwrap();
+        let svcb = decoded.answers[0].data.as_svcb_mut().unwrap();
+        assert_eq!(svcb, &Svcb {
+            priority: 1,
+            target: name("foo.example.com"),
+            params: vec![
+                SvcParam::Alpn(vec![b"h2".to_vec(), b"h3".to_vec()]),
+                SvcParam::Port(53),
+                SvcParam::Ipv4Hint(vec![Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2)]),
+                SvcParam::Ech(vec![1, 2, 3]),
+                SvcParam::Unknown(9, vec![0xff]),
+            ],
+        });
+
+        let mut buf = Vec::new();
+        decoded.encode(&mut buf).unwrap();
+        assert_eq!(buf, pkt);
+
+        let svcb = decoded.answers[0].data.as_svcb_mut().unwrap();
+        assert!(svcb.remove_param(SPK_ECH).is_some());
+        svcb.params.push(SvcParam::Ipv6Hint(vec![Ipv6Addr::LOCALHOST]));
+        svcb.params.push(SvcParam::NoDefaultAlpn);
+        *svcb.param_mut(SPK_IPV4_HINT).unwrap() = SvcParam::Ipv4Hint(vec![Ipv4Addr::LOCALHOST]);
+
+        let mut buf = Vec::new();
+        decoded.encode(&mut buf).unwrap();
+        let redecoded = Packet::decode(&buf).unwrap();
+        let keys: Vec<_> = redecoded.answers[0].data.as_svcb().unwrap().params.iter()
+            .map(|p| p.key())
+            .collect();
+        assert_eq!(keys, vec![SPK_ALPN, SPK_NO_DEFAULT_ALPN, SPK_PORT, SPK_IPV4_HINT,
+            SPK_IPV6_HINT, 9]);
+
+        let svcb = decoded.answers[0].data.as_svcb_mut().unwrap();
+        svcb.params.push(SvcParam::Ipv4Hint(vec![Ipv4Addr::new(192, 0, 2, 3)]));
+        let mut buf = Vec::new();
+        assert_eq!(decoded.encode(&mut buf), Err(Error::BadSvcParam(SPK_IPV4_HINT)));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn edns() {
+        let mut pkt = Packet::new(1, PacketKind::Response, OP_QUERY, Question {
+            name: name("example.com"),
+            kind: RRK_A,
+            class: RRC_IN,
+        });
+        pkt.response_code = RCODE_BAD_COOKIE;
+        pkt.additional_rrs.push(rr("ns.example.com", RRK_A, RRData::Ipv4Addr(Ipv4Addr::LOCALHOST)));
+        pkt.edns = Some(Edns {
+            udp_payload_size: 4096,
+            version: 0,
+            dnssec_ok: true,
+            options: vec![
+                EdnsOption::ClientSubnet(ClientSubnet {
+                    source_prefix_len: 20,
+                    scope_prefix_len: 0,
+                    addr: IpAddr::V4(Ipv4Addr::new(192, 0, 32, 0)),
+                }),
+                EdnsOption::Cookie {
+                    client: [1; 8],
+                    server: vec![2; 8],
+                },
+                EdnsOption::TcpKeepalive(Some(100)),
+                EdnsOption::Padding(3),
+                EdnsOption::Unknown(0xfff0, vec![1, 2]),
+            ],
+        });
+
+        let mut buf = Vec::new();
+        pkt.encode(&mut buf).unwrap();
+        // ARCOUNT includes OPT.
+        assert_eq!(&buf[10..12], &[0, 2]);
+        // Lower 4 bits of the response code in the header.
+        assert_eq!(buf[3] & 0xf, (RCODE_BAD_COOKIE & 0xf) as u8);
+
+        let decoded = Packet::decode(&buf).unwrap();
+        assert_eq!(decoded, pkt);
+        assert_eq!(decoded.edns.as_ref().unwrap().option(EOC_CLIENT_SUBNET).unwrap()
+            .as_client_subnet().unwrap().addr, IpAddr::V4(Ipv4Addr::new(192, 0, 32, 0)));
+
+        let resp = decoded.to_response();
+        assert_eq!(resp.edns, Some(Edns {
+            dnssec_ok: true,
+            ..Default::default()
+        }));
+
+        // Address bits beyond the prefix are cleared.
+        pkt.edns.as_mut().unwrap().options = vec![EdnsOption::ClientSubnet(ClientSubnet {
+            source_prefix_len: 20,
+            scope_prefix_len: 0,
+            addr: IpAddr::V4(Ipv4Addr::new(192, 0, 47, 255)),
+        })];
+        let mut buf = Vec::new();
+        pkt.encode(&mut buf).unwrap();
+        assert_eq!(Packet::decode(&buf).unwrap().edns.unwrap().options[0]
+            .as_client_subnet().unwrap().addr, IpAddr::V4(Ipv4Addr::new(192, 0, 32, 0)));
+    }
+
+    #[test]
+    fn edns_bad() {
+        fn pkt(opt: &[u8]) -> Vec<u8> {
+            let mut r = vec![
+                0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 1,
+                3, b'f', b'o', b'o', 0, 0, 1, 0, 1,
+            ];
+            r.extend_from_slice(opt);
+            r
+        }
+
+        assert!(Packet::decode(&pkt(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0])).is_ok());
+        // Non-root name.
+        assert!(Packet::decode(&pkt(&[0xc0, 12, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 0])).is_err());
+        // Bad cookie length.
+        assert!(Packet::decode(&pkt(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 6, 0, 10, 0, 2, 1, 2]))
+            .is_err());
+        // ECS address longer than the prefix.
+        assert!(Packet::decode(&pkt(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 10,
+            0, 8, 0, 6, 0, 1, 8, 0, 10, 1])).is_err());
+        // ECS address bits beyond the prefix aren't zero.
+        assert!(Packet::decode(&pkt(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 11,
+            0, 8, 0, 7, 0, 1, 20, 0, 192, 0, 0x2f])).is_err());
+        assert!(Packet::decode(&pkt(&[0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 11,
+            0, 8, 0, 7, 0, 1, 20, 0, 192, 0, 0x20])).is_ok());
+    }
+
+    #[test]
+    fn name_presentation() {
+        for (s, labels, display) in [
+            (".", &[][..], "."),
+            ("com", &[&b"com"[..]][..], "com"),
+            ("example.com.", &[b"example", b"com"], "example.com"),
+            ("_sip._tcp.Example.COM", &[b"_sip", b"_tcp", b"Example", b"COM"],
+                "_sip._tcp.Example.COM"),
+            ("a\\.b.c", &[b"a.b", b"c"], "a\\.b.c"),
+            ("a\\\\b", &[b"a\\b"], "a\\\\b"),
+            ("\\000\\255\\x\\032", &[b"\0\xffx "], "\\000\\255x\\032"),
+        ] {
+            let name: Name = s.parse().unwrap();
+            assert_eq!(name.labels().collect::<Vec<_>>(), labels, "{}", s);
+            assert_eq!(name.to_string(), display);
+            assert_eq!(display.parse::<Name>().unwrap(), name);
+        }
+
+        for s in ["", "..", "a..b", ".a", "a\\", "\\256", &"a".repeat(64),
+            &["a"; 128].join(".")]
+        {
+            assert!(s.parse::<Name>().is_err(), "{}", s);
+        }
+        assert!(["a"; 127].join(".").parse::<Name>().is_ok());
+    }
+
+    #[test]
+    fn name_case_insensitive() {
+        use std::collections::hash_map::DefaultHasher;
+
+        fn hash(n: &Name) -> u64 {
+            let mut h = DefaultHasher::new();
+            n.hash(&mut h);
+            h.finish()
+        }
+
+        let a = name("Example.COM");
+        let b = name("example.com");
+        assert_eq!(a, b);
+        assert_eq!(hash(&a), hash(&b));
+        assert_eq!(a.cmp(&b), Ordering::Equal);
+        assert_eq!(a.to_string(), "Example.COM");
+        assert_ne!(a, name("example.co"));
+        assert_ne!(a, name("example.com.x"));
+    }
+
+    #[test]
+    fn name_canonical_order() {
+        // RFC 4034 section 6.1.
+        let names = [
+            ".",
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            "zABC.a.EXAMPLE",
+            "z.example",
+            "\\001.z.example",
+            "*.z.example",
+            "\\200.z.example",
+        ];
+        for (i, a) in names.iter().enumerate() {
+            for (j, b) in names.iter().enumerate() {
+                assert_eq!(name(a).cmp(&name(b)), i.cmp(&j), "{} {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn name_labels() {
+        let n = name("www.Example.com");
+        assert_eq!(n.label_count(), 3);
+        assert_eq!(n.labels().collect::<Vec<_>>(), vec![&b"www"[..], b"Example", b"com"]);
+        assert_eq!(n.labels().next_back(), Some(&b"com"[..]));
+        assert_eq!(n.parent(), name("example.com"));
+        assert_eq!(n.parent().parent().parent(), name("."));
+        assert_eq!(name(".").parent(), name("."));
+
+        assert!(n.is_subdomain_of(&n));
+        assert!(n.is_subdomain_of(&name("EXAMPLE.com")));
+        assert!(n.is_subdomain_of(&name("com")));
+        assert!(n.is_subdomain_of(&name(".")));
+        assert!(!n.is_subdomain_of(&name("ample.com")));
+        assert!(!n.is_subdomain_of(&name("www")));
+        assert!(!name("com").is_subdomain_of(&n));
+
+        assert_eq!(Name::from_labels(["www", "Example", "com"]), Some(n));
+        assert_eq!(Name::from_labels(Vec::<&[u8]>::new()), Some(name(".")));
+        assert_eq!(Name::from_labels(["a", ""]), None);
+        assert_eq!(Name::from_labels(["a".repeat(64)]), None);
+        assert_eq!(Name::from_labels(["a"; 128]), None);
+        assert_eq!(Name::from_labels(["a"; 127]).unwrap().label_count(), 127);
+    }
+
+    #[test]
+    fn name_is_hostname() {
+        assert!(name(".").is_hostname());
+        assert!(name("www-1.example.com").is_hostname());
+        assert!(!name("_dmarc.example.com").is_hostname());
+        assert!(!name("-a.example.com").is_hostname());
+        assert!(!name("a-.example.com").is_hostname());
+        assert!(!name("a\\.b").is_hostname());
+    }
+
+    #[test]
+    fn decode_arbitrary_label_octets() {
+        let pkt = [
+            0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0,
+            6, b'_', b'd', b'm', b'a', b'r', b'c', 3, b'a', 0, 0xff, 0, 0, 16, 0, 1,
+        ];
+        let decoded = Packet::decode(&pkt).unwrap();
+        assert_eq!(decoded.question.name.to_string(), "_dmarc.a\\000\\255");
+        assert_eq!(decoded.question.name.parent(), name("a\\000\\255"));
+
+        let mut buf = Vec::new();
+        decoded.encode(&mut buf).unwrap();
+        assert_eq!(&buf[..], &pkt[..]);
+    }
+
+    #[test]
+    fn decode_pointer_loop() {
+        let pkt = [
+            0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0,
+            // Points to itself.
+            0xc0, 12, 0, 1, 0, 1,
+        ];
+        assert_eq!(Packet::decode(&pkt), Err(Error::PointerLoop));
+
+        let pkt = [
+            0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0,
+            // Points forward.
+            0xc0, 14, 0, 0, 0, 1, 0, 1,
+        ];
+        assert_eq!(Packet::decode(&pkt), Err(Error::PointerLoop));
+    }
+
+    #[test]
+    fn decode_errors() {
+        let header = [0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        let pkt = |body: &[u8]| {
+            let mut r = header.to_vec();
+            r.extend_from_slice(body);
+            r
+        };
+
+        assert_eq!(Packet::decode(&header[..11]), Err(Error::TruncatedHeader));
+        assert_eq!(Packet::decode(&[0; 12]), Err(Error::BadQuestionCount(0)));
+        let mut two = pkt(&[0, 0, 1, 0, 1, 0, 0, 1, 0, 1]);
+        two[5] = 2;
+        assert_eq!(Packet::decode(&two), Err(Error::BadQuestionCount(2)));
+        assert_eq!(Packet::decode(&pkt(&[3, b'f', b'o'])), Err(Error::UnexpectedEnd));
+        assert_eq!(Packet::decode(&pkt(&[0, 0, 1, 0])), Err(Error::UnexpectedEnd));
+        assert_eq!(Packet::decode(&pkt(&[0x40, 0, 0, 1, 0, 1])), Err(Error::BadLabel));
+
+        let mut long = Vec::new();
+        for _ in 0..4 {
+            long.push(63);
+            long.extend_from_slice(&[b'a'; 63]);
+        }
+        long.extend_from_slice(&[0, 0, 1, 0, 1]);
+        assert_eq!(Packet::decode(&pkt(&long)), Err(Error::NameTooLong));
+    }
+
+    #[test]
+    fn decode_rr_data_len_mismatch() {
+        fn pkt(kind: RRKind, rr_data: &[u8], data_len: u8) -> Vec<u8> {
+            let mut r = vec![
+                0, 1, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0,
+                3, b'f', b'o', b'o', 0, 0, 1, 0, 1,
+                0xc0, 12, 0, kind as u8, 0, 1, 0, 0, 0, 60, 0, data_len,
+            ];
+            r.extend_from_slice(rr_data);
+            // Another RR after the malformed one.
+            r.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 1, 2, 3, 4]);
+            r
+        }
+
+        let cname = b"\x03bar\xc0\x0c";
+        let soa = b"\xc0\x0c\xc0\x0c\0\0\0\x01\0\0\0\x02\0\0\0\x03\0\0\0\x04\0\0\0\x05";
+        let mx = b"\0\x0a\xc0\x0c";
+        let srv = b"\0\x01\0\x02\0\x03\xc0\x0c";
+        let corpus: &[(RRKind, &[u8])] = &[
+            (RRK_A, &[1, 2, 3, 4]),
+            (RRK_AAAA, &[0; 16]),
+            (RRK_CNAME, cname),
+            (RRK_SOA, soa),
+            (RRK_MX, mx),
+            (RRK_SRV, srv),
+        ];
+        for &(kind, rr_data) in corpus {
+            let len = rr_data.len() as u8;
+            let decoded = Packet::decode(&pkt(kind, rr_data, len)).unwrap();
+            assert_eq!(decoded.answers.len(), 2);
+
+            for data_len in [0, 1, len - 1, len + 1, len + 2] {
+                let mut rr_data = rr_data.to_vec();
+                rr_data.resize(data_len.max(len) as usize, 0);
+                assert_eq!(Packet::decode(&pkt(kind, &rr_data, data_len)),
+                    Err(Error::RRDataLenMismatch { kind }), "{} {}", kind, data_len);
+            }
+        }
+
+        // Pointer in the name still resolves against the whole packet.
+        let decoded = Packet::decode(&pkt(RRK_CNAME, cname, cname.len() as u8)).unwrap();
+        assert_eq!(decoded.answers[0].data, RRData::Name(name("bar.foo")));
+
+        // TXT must have at least one character-string.
+        assert_eq!(Packet::decode(&pkt(RRK_TXT, &[], 0)), Err(Error::BadRRData { kind: RRK_TXT }));
+    }
+
+    /// Mutates valid packets and checks decoding never panics and whatever decodes successfully
+    /// round-trips.
+    #[test]
+    fn decode_fuzz() {
+        let mut pkt = Packet::new(0x1234, PacketKind::Response, OP_QUERY, Question {
+            name: name("www.example.com"),
+            kind: RRK_HTTPS,
+            class: RRC_IN,
+        });
+        pkt.answers = vec![
+            rr("www.example.com", RRK_CNAME, RRData::Name(name("example.com"))),
+            rr("example.com", RRK_HTTPS, RRData::Svcb(Svcb {
+                priority: 1,
+                target: name("."),
+                params: vec![
+                    SvcParam::Alpn(vec![b"h2".to_vec()]),
+                    SvcParam::Ipv4Hint(vec![Ipv4Addr::LOCALHOST]),
+                ],
+            })),
+            rr("example.com", RRK_MX, RRData::Mx(Mx {
+                preference: 1,
+                exchange: name("mx.example.com"),
+            })),
+            rr("example.com", RRK_TXT, RRData::Txt(vec![b"abc".to_vec()])),
+        ];
+        pkt.authorities = vec![
+            rr("example.com", RRK_SOA, RRData::Soa(Soa {
+                primary_name: name("ns.example.com"),
+                responsible_name: name("root.example.com"),
+                serial: 1,
+                refresh_secs: 2,
+                retry_secs: 3,
+                expire_secs: 4,
+                min_ttl_secs: 5,
+            })),
+        ];
+        pkt.edns = Some(Edns {
+            options: vec![EdnsOption::Cookie { client: [1; 8], server: vec![] }],
+            ..Default::default()
+        });
+        let mut valid = Vec::new();
+        pkt.encode(&mut valid).unwrap();
+
+        fn check(buf: &[u8]) {
+            if let Ok(pkt) = Packet::decode(buf) {
+                let mut buf = Vec::new();
+                pkt.encode(&mut buf).unwrap();
+                assert_eq!(Packet::decode(&buf).unwrap(), pkt);
+            }
+        }
+
+        for len in 0..valid.len() {
+            check(&valid[..len]);
+        }
+
+        // xorshift
+        let mut rnd = 0x2545f4914f6cdd1d_u64;
+        let mut next = move || {
+            rnd ^= rnd << 13;
+            rnd ^= rnd >> 7;
+            rnd ^= rnd << 17;
+            rnd
+        };
+        for _ in 0..20000 {
+            let mut buf = valid.clone();
+            for _ in 0..next() % 4 + 1 {
+                let i = next() as usize % buf.len();
+                buf[i] = match next() % 4 {
+                    0 => 0,
+                    1 => 0xff,
+                    2 => buf[i].wrapping_add(1),
+                    _ => next() as u8,
+                };
+            }
+            check(&buf);
+        }
+    }
+
+    #[test]
+    fn encode_format_error() {
+        let mut buf = vec![0xff];
+        // ID 0x1234, opcode STATUS, RD, QDCOUNT 2.
+        assert!(Packet::encode_format_error(&[0x12, 0x34, 0x11, 0x00, 0, 2, 0, 0, 0, 0, 0, 0, 1],
+            &mut buf));
+        assert_eq!(buf, vec![0xff, 0x12, 0x34, 0x91, 0x01, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let mut buf = Vec::new();
+        assert!(!Packet::encode_format_error(&[0; 11], &mut buf));
+        // Response.
+        assert!(!Packet::encode_format_error(&[0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0], &mut buf));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn encode_truncated() {
+        let mut pkt = Packet::new(1, PacketKind::Response, OP_QUERY, Question {
+            name: name("example.com"),
+            kind: RRK_A,
+            class: RRC_IN,
+        });
+        let a = |i| rr("example.com", RRK_A, RRData::Ipv4Addr(Ipv4Addr::new(10, 0, 0, i)));
+        let ns = |i| rr("example.com", RRK_NS, RRData::Ns(name(&format!("ns{}.example.com", i))));
+        pkt.answers = (0..10).map(a).collect();
+        pkt.authorities = vec![ns(1), ns(2)];
+        pkt.additional_rrs = vec![
+            rr("ns1.example.com", RRK_A, RRData::Ipv4Addr(Ipv4Addr::LOCALHOST)),
+            rr("ns2.example.com", RRK_A, RRData::Ipv4Addr(Ipv4Addr::LOCALHOST)),
+        ];
+        pkt.edns = Some(Edns::default());
+
+        let mut full = Vec::new();
+        pkt.encode(&mut full).unwrap();
+
+        let encode = |max_len| {
+            let mut buf = Vec::new();
+            pkt.encode_truncated(&mut buf, max_len).unwrap();
+            Packet::decode(&buf).unwrap()
+        };
+
+        assert_eq!(encode(full.len()), pkt);
+
+        // Additional RRset is dropped without setting TC.
+        let r = encode(full.len() - 1);
+        assert!(!r.truncated);
+        assert_eq!(r.additional_rrs.len(), 1);
+        assert_eq!(r.edns, pkt.edns);
+
+        // Authority RRset is dropped as a whole.
+        let mut expected = pkt.clone();
+        expected.truncated = true;
+        expected.authorities.clear();
+        expected.additional_rrs.clear();
+        let mut buf = Vec::new();
+        expected.encode(&mut buf).unwrap();
+        assert_eq!(encode(buf.len() + 10), expected);
+
+        // Answer RRset is dropped as a whole.
+        let r = encode(full.len() / 2);
+        assert!(r.truncated);
+        assert!(r.answers.is_empty());
+        assert!(r.authorities.is_empty());
+        assert!(r.additional_rrs.is_empty());
+        assert_eq!(r.edns, pkt.edns);
+
+        // Even the question doesn't fit.
+        let mut buf = vec![0xff];
+        pkt.encode_truncated(&mut buf, 20).unwrap();
+        assert_eq!(buf, vec![0xff, 0, 1, 0x82, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn max_udp_payload_size() {
+        let mut pkt = Packet::new(1, PacketKind::Query, OP_QUERY, Question {
+            name: name("example.com"),
+            kind: RRK_A,
+            class: RRC_IN,
+        });
+        assert_eq!(pkt.max_udp_payload_size(), 512);
+        pkt.edns = Some(Edns { udp_payload_size: 100, ..Default::default() });
+        assert_eq!(pkt.max_udp_payload_size(), 512);
+        pkt.edns = Some(Edns { udp_payload_size: 4096, ..Default::default() });
+        assert_eq!(pkt.max_udp_payload_size(), 4096);
+    }
+
+    #[test]
+    fn encode_errors() {
+        let mut pkt = Packet::new(1, PacketKind::Response, OP_QUERY, Question {
+            name: name("example.com"),
+            kind: RRK_TXT,
+            class: RRC_IN,
+        });
+        pkt.answers.push(rr("example.com", RRK_TXT, RRData::Txt(vec![vec![0; 256]])));
+        let mut buf = vec![1, 2, 3];
+        assert_eq!(pkt.encode(&mut buf), Err(Error::CharStringTooLong));
+        assert_eq!(buf, vec![1, 2, 3]);
+
+        pkt.answers = vec![rr("example.com", RRK_TXT, RRData::Txt(vec![vec![0; 255]; 257]))];
+        assert_eq!(pkt.encode(&mut buf), Err(Error::RRDataTooLong { kind: RRK_TXT }));
+
+        pkt.answers = vec![rr("example.com", RRK_TXT, RRData::Txt(vec![]))];
+        assert_eq!(pkt.encode(&mut buf), Err(Error::BadRRData { kind: RRK_TXT }));
+
+        let a = rr("example.com", RRK_A, RRData::Ipv4Addr(Ipv4Addr::LOCALHOST));
+        pkt.answers = vec![a; 0x10000];
+        assert_eq!(pkt.encode(&mut buf), Err(Error::SectionTooLong));
+
+        pkt.answers.clear();
+        pkt.response_code = RCODE_BAD_COOKIE;
+        assert_eq!(pkt.encode(&mut buf), Err(Error::BadResponseCode(RCODE_BAD_COOKIE)));
+        pkt.edns = Some(Edns::default());
+        assert!(pkt.encode(&mut buf).is_ok());
+    }
+}
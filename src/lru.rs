@@ -0,0 +1,148 @@
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::hash::Hash;
+use std::ops::RangeBounds;
+
+use linked_hash_set::LinkedHashSet;
+
+pub struct LruCache<K, V> {
+    map: BTreeMap<K, V>,
+    order: LinkedHashSet<K>,
+    max_len: usize,
+}
+
+impl<K, V> LruCache<K, V>
+    where
+        K: Clone + Eq + Hash + Ord,
+{
+    pub fn new(max_len: usize) -> Self {
+        assert!(max_len > 0);
+        Self {
+            map: BTreeMap::new(),
+            order: LinkedHashSet::new(),
+            max_len,
+        }
+    }
+
+    pub fn range<T, R>(&mut self, range: R, touch: bool, mut f: impl FnMut(&K, &V))
+        where
+            T: ?Sized + Ord,
+            K: Borrow<T>,
+            R: RangeBounds<T>,
+    {
+        for (k, v) in self.map.range(range) {
+            if touch {
+                Self::touch(&mut self.order, k);
+            }
+            f(k, v);
+        }
+    }
+
+    pub fn remove_range<T, R>(&mut self, range: R)
+    where
+        T: ?Sized + Ord,
+        K: Borrow<T>,
+        R: RangeBounds<T>,
+    {
+        let mut keys = Vec::new();
+        self.range(range, false, |k, _| keys.push(k.clone()));
+        for key in &keys {
+            self.map.remove(key.borrow());
+            self.order.remove::<K>(key);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.map.contains_key(key)
+    }
+
+    /// Returns the value for `key` marking it as the most recently used.
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let r = self.map.get_mut(key)?;
+        Self::touch(&mut self.order, key);
+        Some(r)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if self.map.len() == self.max_len && !self.map.contains_key(&key) {
+            self.pop_lru();
+        }
+        Self::touch(&mut self.order, &key);
+        self.map.insert(key, value)
+    }
+
+    /// Removes the least recently used entry.
+    pub fn pop_lru(&mut self) -> Option<(K, V)> {
+        let k = self.order.pop_front()?;
+        let v = self.map.remove(&k).unwrap();
+        Some((k, v))
+    }
+
+    fn touch(order: &mut LinkedHashSet<K>, key: &K) {
+        if !order.refresh(key) {
+            order.insert(key.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(cache: &mut LruCache<u32, u32>) -> Vec<(u32, u32)> {
+        let mut r = Vec::new();
+        cache.range::<u32, _>(.., false, |&k, &v| r.push((k, v)));
+        r
+    }
+
+    #[test]
+    fn eviction_order() {
+        let mut cache = LruCache::new(2);
+        for i in 1..=3 {
+            cache.insert(i, i);
+        }
+        assert_eq!(entries(&mut cache), [(2, 2), (3, 3)]);
+        assert_eq!(cache.pop_lru(), Some((2, 2)));
+        assert_eq!(cache.pop_lru(), Some((3, 3)));
+        assert_eq!(cache.pop_lru(), None);
+    }
+
+    #[test]
+    fn overwrite_doesnt_evict() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        assert_eq!(cache.insert(1, 10), Some(1));
+        assert_eq!(entries(&mut cache), [(1, 10), (2, 2)]);
+        assert_eq!(cache.pop_lru(), Some((2, 2)));
+    }
+
+    #[test]
+    fn get_mut_refreshes() {
+        let mut cache = LruCache::new(2);
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+        *cache.get_mut(&1).unwrap() = 10;
+        assert_eq!(cache.get_mut(&3), None);
+        cache.insert(3, 3);
+        assert_eq!(entries(&mut cache), [(1, 10), (3, 3)]);
+    }
+
+    #[test]
+    fn insert_after_remove_range() {
+        let mut cache = LruCache::new(2);
+        for i in 1..=3 {
+            cache.insert(i, i);
+        }
+        cache.remove_range(2..=3);
+        assert_eq!(cache.len(), 0);
+        for i in 4..=6 {
+            cache.insert(i, i);
+        }
+        assert_eq!(entries(&mut cache), [(5, 5), (6, 6)]);
+    }
+}
@@ -9,6 +9,7 @@ use std::time::Duration;
 
 use anyhow::Result;
 use tokio::net::UdpSocket;
+use tracing::error;
 
 use crate::cache::Cache;
 use crate::dns::{OP_QUERY, Packet, PacketKind, RCODE_NX_DOMAIN};
@@ -24,6 +25,8 @@ mod dns;
 mod server;
 mod process;
 mod cache;
+mod lru;
+mod net;
 mod upstream;
 
 #[tokio::main(flavor = "multi_thread")]
@@ -70,8 +73,34 @@ async fn main() {
     ]);
 
     let pr = Processor::new(rule_lists);
-    let s = Server::start(&["0.0.0.0:53".parse().unwrap()], pr).await.unwrap();
-    tokio::time::sleep(Duration::from_secs(10000)).await;
+    let s = Server::start(server::Config {
+        addrs: vec!["0.0.0.0:53".parse().unwrap()],
+        ..Default::default()
+    }, pr).await.unwrap();
+
+    shutdown_signal().await;
+    s.shutdown(Duration::from_secs(5)).await;
+}
+
+/// Completes on SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = sigterm.recv() => {}
+            },
+            Err(err) => {
+                error!(?err, "error installing SIGTERM handler");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
 
 async fn foo() -> Result<()> {
@@ -87,6 +116,8 @@ async fn foo() -> Result<()> {
         truncated: false,
         recursion_desired: true,
         recursion_available: false,
+        authentic_data: false,
+        checking_disabled: false,
         response_code: 0,
         question: Question {
             name: "google.com".parse().unwrap(),
@@ -95,11 +126,12 @@ async fn foo() -> Result<()> {
         },
         answers: vec![],
         authorities: vec![],
-        additional_rrs: vec![]
+        additional_rrs: vec![],
+        edns: None,
     };
 
     let mut b = Vec::new();
-    msg.encode(&mut b);
+    msg.encode(&mut b)?;
 
     dbg!(&b[..]);
     socket.send(&b).await.unwrap();
@@ -0,0 +1,17 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Returns `addr` with the bits past the first `prefix_len` cleared. Prefix length over the address
+/// length leaves the address as is.
+pub fn network_addr(addr: IpAddr, prefix_len: u8) -> IpAddr {
+    match addr {
+        IpAddr::V4(v) => {
+            let mask = u32::MAX.checked_shl(32u32.saturating_sub(prefix_len as u32)).unwrap_or(0);
+            Ipv4Addr::from(u32::from(v) & mask).into()
+        }
+        IpAddr::V6(v) => {
+            let mask = u128::MAX.checked_shl(128u32.saturating_sub(prefix_len as u32))
+                .unwrap_or(0);
+            Ipv6Addr::from(u128::from(v) & mask).into()
+        }
+    }
+}
@@ -24,19 +24,20 @@ impl Processor {
         }))
     }
 
-    pub async fn process(&self, mut query: Packet) -> Result<Option<Packet>> {
+    pub async fn process(&self, mut query: Packet, origin: Origin) -> Result<Option<Packet>> {
         if query.kind != PacketKind::Query || query.op_kind != OP_QUERY {
             debug!("not a standard query");
             return Ok(None);
         }
-        if query.question.class != RRC_IN {
-            debug!("not IN class");
-            return Ok(None);
+        if query.edns.as_ref().map(|v| v.version > 0).unwrap_or(false) {
+            debug!("unsupported EDNS version");
+            return Ok(Some(query.to_response_with_code(RCODE_BAD_VERSION)));
         }
 
         query.authoritative = false;
         query.truncated = false;
         query.recursion_available = false;
+        query.authentic_data = false;
         query.response_code = RCODE_NO_ERROR;
         query.additional_rrs.clear();
         query.answers.clear();
@@ -51,6 +52,8 @@ impl Processor {
 
         let mut ctx = Context {
             query,
+            origin,
+            metadata: Metadata::default(),
         };
         let resp = 'outer: loop {
             for (rule_idx, rule) in rule_list.iter().enumerate() {
@@ -64,7 +67,7 @@ impl Processor {
                         ActionResult::Continue => {}
                         ActionResult::Return(resp) => break 'outer resp,
                         ActionResult::RuleList(rl) => {
-                            if !seen.insert(rule_list_id.clone().into()) {
+                            if !seen.insert(rule_list_id.clone()) {
                                 bail!("rule list cycle detected: {:?} -> '{}'", seen, rl);
                             }
                             rule_list = &self.0.rule_lists[&rl];
@@ -1,9 +1,17 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
 use anyhow::Result;
 use async_trait::async_trait;
 
 use crate::dns::Packet;
 
+pub mod client;
+pub mod domain;
 pub mod forward;
+pub mod pattern;
+pub mod query;
 
 pub type RuleListId = String;
 pub type RuleListIdRef<'a> = &'a str;
@@ -29,6 +37,57 @@ pub trait Action: Send + Sync {
 
 pub struct Context {
     pub query: Packet,
+    pub origin: Origin,
+    pub metadata: Metadata,
+}
+
+/// Where the query was received from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Origin {
+    /// Address of the client. For DoH this is the address of the HTTP peer.
+    pub client: SocketAddr,
+    /// Local address the query was received on.
+    pub local: SocketAddr,
+    pub transport: Transport,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    /// DNS over TLS.
+    Tls,
+    /// DNS over HTTPS.
+    Https,
+    /// DNS over QUIC.
+    Quic,
+}
+
+/// Per-query values keyed by type, for passing data between rules.
+#[derive(Default)]
+pub struct Metadata(HashMap<TypeId, Box<dyn Any + Send + Sync>>);
+
+impl Metadata {
+    /// Inserts the value replacing and returning the previous value of the same type.
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.0.insert(TypeId::of::<T>(), Box::new(value))
+            .map(|v| *v.downcast().unwrap())
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.0.get(&TypeId::of::<T>())
+            .map(|v| v.downcast_ref().unwrap())
+    }
+
+    pub fn get_mut<T: Any + Send + Sync>(&mut self) -> Option<&mut T> {
+        self.0.get_mut(&TypeId::of::<T>())
+            .map(|v| v.downcast_mut().unwrap())
+    }
+
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        self.0.remove(&TypeId::of::<T>())
+            .map(|v| *v.downcast().unwrap())
+    }
 }
 
 pub struct Rule {
@@ -47,4 +106,47 @@ pub fn any() -> impl Matcher {
     }
 
     Any
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::dns::*;
+
+    use super::*;
+
+    /// Returns context of A query for `name` received over UDP.
+    pub fn context(name: &str) -> Context {
+        let mut query = Packet::new(1, PacketKind::Query, OP_QUERY, Question {
+            name: name.parse().unwrap(),
+            kind: RRK_A,
+            class: RRC_IN,
+        });
+        query.recursion_desired = true;
+        Context {
+            query,
+            origin: Origin {
+                client: "127.0.0.1:1234".parse().unwrap(),
+                local: "127.0.0.1:53".parse().unwrap(),
+                transport: Transport::Udp,
+            },
+            metadata: Metadata::default(),
+        }
+    }
+
+    #[test]
+    fn metadata() {
+        #[derive(Debug, Eq, PartialEq)]
+        struct Tag(&'static str);
+
+        let mut m = Metadata::default();
+        assert_eq!(m.get::<Tag>(), None);
+        assert_eq!(m.insert(Tag("a")), None);
+        assert_eq!(m.insert(1u32), None);
+        assert_eq!(m.insert(Tag("b")), Some(Tag("a")));
+        *m.get_mut::<u32>().unwrap() += 1;
+        assert_eq!(m.get::<Tag>(), Some(&Tag("b")));
+        assert_eq!(m.get::<u32>(), Some(&2));
+        assert_eq!(m.remove::<u32>(), Some(2));
+        assert_eq!(m.get::<u32>(), None);
+    }
+}
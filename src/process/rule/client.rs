@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+
+use crate::net::network_addr;
+
+use super::*;
+
+/// IP network in CIDR notation. The host bits of the address are cleared.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Result<Self> {
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_len {
+            return Err(anyhow!("prefix length {} is too long for {}", prefix_len, addr));
+        }
+        Ok(Self {
+            addr: network_addr(addr, prefix_len),
+            prefix_len,
+        })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+}
+
+/// Parses `addr/prefix_len` or a bare address which is a single host network.
+impl FromStr for IpNet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse()
+            .map_err(|_| anyhow!("invalid network address: {}", s))?;
+        let prefix_len = match prefix_len {
+            Some(v) => v.parse().map_err(|_| anyhow!("invalid prefix length: {}", s))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Self::new(addr, prefix_len)
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Set of IPv4 and IPv6 networks. Lookup cost depends on the address length rather than on the
+/// number of networks.
+#[derive(Clone, Debug, Default)]
+pub struct IpNetSet {
+    v4: PrefixTrie,
+    v6: PrefixTrie,
+}
+
+impl IpNetSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, net: IpNet) {
+        match net.addr {
+            IpAddr::V4(v) => self.v4.insert((u32::from(v) as u128) << 96, net.prefix_len),
+            IpAddr::V6(v) => self.v6.insert(u128::from(v), net.prefix_len),
+        }
+    }
+
+    /// Returns `true` if `ip` belongs to any of the networks. IPv4-mapped IPv6 addresses are
+    /// looked up as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(v) => self.v4.contains((u32::from(v) as u128) << 96, 32),
+            IpAddr::V6(v) => if let Some(v) = v.to_ipv4_mapped() {
+                self.v4.contains((u32::from(v) as u128) << 96, 32)
+            } else {
+                self.v6.contains(u128::from(v), 128)
+            }
+        }
+    }
+}
+
+impl FromIterator<IpNet> for IpNetSet {
+    fn from_iter<T: IntoIterator<Item=IpNet>>(iter: T) -> Self {
+        let mut r = Self::new();
+        for net in iter {
+            r.insert(net);
+        }
+        r
+    }
+}
+
+/// Binary trie over the address bits, most significant first.
+#[derive(Clone, Debug)]
+struct PrefixTrie {
+    /// Root is the first node. Child index of 0 means there's no child since the root can't be
+    /// a child.
+    nodes: Vec<TrieNode>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct TrieNode {
+    children: [u32; 2],
+    /// The path to this node is one of the prefixes.
+    terminal: bool,
+}
+
+impl Default for PrefixTrie {
+    fn default() -> Self {
+        Self {
+            nodes: vec![TrieNode::default()],
+        }
+    }
+}
+
+impl PrefixTrie {
+    fn insert(&mut self, key: u128, len: u8) {
+        let mut node = 0;
+        for i in 0..len {
+            if self.nodes[node].terminal {
+                // Covered by a shorter prefix.
+                return;
+            }
+            let bit = Self::bit(key, i);
+            node = match self.nodes[node].children[bit] {
+                0 => {
+                    let child = self.nodes.len();
+                    self.nodes.push(TrieNode::default());
+                    self.nodes[node].children[bit] = child as u32;
+                    child
+                }
+                child => child as usize,
+            };
+        }
+        let node = &mut self.nodes[node];
+        node.terminal = true;
+        // Longer prefixes are now redundant.
+        node.children = [0, 0];
+    }
+
+    fn contains(&self, key: u128, len: u8) -> bool {
+        let mut node = 0;
+        for i in 0..len {
+            if self.nodes[node].terminal {
+                return true;
+            }
+            node = match self.nodes[node].children[Self::bit(key, i)] {
+                0 => return false,
+                child => child as usize,
+            };
+        }
+        self.nodes[node].terminal
+    }
+
+    fn bit(key: u128, i: u8) -> usize {
+        (key >> (127 - i) & 1) as usize
+    }
+}
+
+/// Named client groups, each defined once and shared by the rules referencing it.
+#[derive(Clone, Debug, Default)]
+pub struct ClientGroups(HashMap<String, Arc<IpNetSet>>);
+
+impl ClientGroups {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Defines group `name` replacing the existing group with the same name.
+    pub fn insert(&mut self, name: impl Into<String>, nets: IpNetSet) {
+        self.0.insert(name.into(), Arc::new(nets));
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<IpNetSet>> {
+        self.0.get(name)
+    }
+
+    /// Returns matcher of the clients in group `name`.
+    pub fn matcher(&self, name: &str) -> Result<impl Matcher> {
+        let nets = self.get(name)
+            .ok_or_else(|| anyhow!("unknown client group: {}", name))?;
+        Ok(client_in(nets.clone()))
+    }
+}
+
+struct ClientIn(Arc<IpNetSet>);
+
+#[async_trait]
+impl Matcher for ClientIn {
+    async fn matches(&self, ctx: &Context) -> Result<bool> {
+        Ok(self.0.contains(ctx.origin.client.ip()))
+    }
+}
+
+/// Matches queries from the clients in any of `nets`.
+pub fn client_in(nets: Arc<IpNetSet>) -> impl Matcher {
+    ClientIn(nets)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::process::rule::tests::context;
+
+    use super::*;
+
+    fn set(nets: &[&str]) -> IpNetSet {
+        nets.iter().map(|v| v.parse().unwrap()).collect()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let net: IpNet = "10.1.2.3/8".parse().unwrap();
+        assert_eq!(net.to_string(), "10.0.0.0/8");
+        assert_eq!("2001:db8::1".parse::<IpNet>().unwrap().to_string(), "2001:db8::1/128");
+        assert_eq!("::/0".parse::<IpNet>().unwrap().prefix_len(), 0);
+        for s in ["10.0.0.0/33", "::/129", "10.0.0.0/", "10.0.0/8", "example.com"] {
+            assert!(s.parse::<IpNet>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn contains() {
+        let s = set(&["10.0.0.0/8", "192.168.1.0/24", "192.168.1.0/25", "172.16.5.4",
+            "2001:db8:1::/48"]);
+        for v in ["10.255.0.1", "192.168.1.200", "172.16.5.4", "::ffff:10.0.0.1",
+            "2001:db8:1:ff::1"]
+        {
+            assert!(s.contains(ip(v)), "{}", v);
+        }
+        for v in ["11.0.0.1", "192.168.2.1", "172.16.5.5", "2001:db8:2::1", "::a00:1"] {
+            assert!(!s.contains(ip(v)), "{}", v);
+        }
+
+        // Shorter prefix inserted after the longer one.
+        let s = set(&["192.168.1.128/25", "192.168.0.0/16"]);
+        assert!(s.contains(ip("192.168.2.1")));
+
+        assert!(!IpNetSet::new().contains(ip("10.0.0.1")));
+        let s = set(&["0.0.0.0/0"]);
+        assert!(s.contains(ip("1.2.3.4")));
+        assert!(!s.contains(ip("::1")));
+    }
+
+    #[tokio::test]
+    async fn groups() {
+        let mut groups = ClientGroups::new();
+        groups.insert("kids", set(&["192.168.1.0/24", "fd00:1::/64"]));
+        groups.insert("servers", set(&["192.168.10.0/24"]));
+        assert!(groups.matcher("guests").is_err());
+
+        let kids = groups.matcher("kids").unwrap();
+        let servers = groups.matcher("servers").unwrap();
+        for (client, is_kid) in [("192.168.1.5:1000", true), ("[fd00:1::5]:1000", true),
+            ("192.168.10.5:1000", false)]
+        {
+            let mut ctx = context("example.com");
+            ctx.origin.client = client.parse().unwrap();
+            assert_eq!(kids.matches(&ctx).await.unwrap(), is_kid);
+            assert_eq!(servers.matches(&ctx).await.unwrap(), !is_kid);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::dns::Name;
+
+use super::*;
+
+/// Domain name pattern.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum DomainPattern {
+    /// Matches the name only.
+    Exact(Name),
+    /// Matches the name and all its subdomains.
+    Suffix(Name),
+    /// Matches the subdomains of the name but not the name itself, i.e. `*.example.com`.
+    Wildcard(Name),
+}
+
+/// Set of domain name patterns. Names are compared ASCII case-insensitively. Lookup cost depends
+/// on the number of labels in the name rather than on the number of patterns.
+#[derive(Clone, Debug, Default)]
+pub struct DomainSet {
+    /// Trie keyed by the lower-cased labels starting from the rightmost one.
+    root: Node,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Node {
+    children: HashMap<Box<[u8]>, Node>,
+    exact: bool,
+    suffix: bool,
+    wildcard: bool,
+}
+
+impl DomainSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, pattern: DomainPattern) {
+        let (name, f): (_, fn(&mut Node)) = match pattern {
+            DomainPattern::Exact(v) => (v, |n| n.exact = true),
+            DomainPattern::Suffix(v) => (v, |n| n.suffix = true),
+            DomainPattern::Wildcard(v) => (v, |n| n.wildcard = true),
+        };
+        let mut node = &mut self.root;
+        for label in name.labels().rev() {
+            node = node.children.entry(label.to_ascii_lowercase().into()).or_default();
+        }
+        f(node);
+    }
+
+    pub fn contains(&self, name: &Name) -> bool {
+        let mut node = &self.root;
+        let mut buf = [0; Name::MAX_LABEL_LEN];
+        for label in name.labels().rev() {
+            // There are labels left so the name is a subdomain of the node.
+            if node.suffix || node.wildcard {
+                return true;
+            }
+            let lower = &mut buf[..label.len()];
+            lower.copy_from_slice(label);
+            lower.make_ascii_lowercase();
+            node = if let Some(v) = node.children.get(&*lower) {
+                v
+            } else {
+                return false;
+            };
+        }
+        node.exact || node.suffix
+    }
+}
+
+impl FromIterator<DomainPattern> for DomainSet {
+    fn from_iter<T: IntoIterator<Item=DomainPattern>>(iter: T) -> Self {
+        let mut r = Self::new();
+        for pattern in iter {
+            r.insert(pattern);
+        }
+        r
+    }
+}
+
+struct DomainIn(Arc<DomainSet>);
+
+#[async_trait]
+impl Matcher for DomainIn {
+    async fn matches(&self, ctx: &Context) -> Result<bool> {
+        Ok(self.0.contains(&ctx.query.question.name))
+    }
+}
+
+/// Matches queries for the names matching any of the patterns in `domains`.
+pub fn domain_in(domains: Arc<DomainSet>) -> impl Matcher {
+    DomainIn(domains)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::process::rule::tests::context;
+
+    use super::*;
+
+    fn name(s: &str) -> Name {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn contains() {
+        let set: DomainSet = [
+            DomainPattern::Exact(name("exact.com")),
+            DomainPattern::Suffix(name("Suffix.com")),
+            DomainPattern::Wildcard(name("wildcard.com")),
+            DomainPattern::Exact(name("a.wildcard.com")),
+        ].into_iter().collect();
+        for v in ["exact.com", "EXACT.com", "suffix.com", "a.b.SUFFIX.com", "a.wildcard.com",
+            "a.b.wildcard.com"]
+        {
+            assert!(set.contains(&name(v)), "{}", v);
+        }
+        for v in ["a.exact.com", "com", "notsuffix.com", "wildcard.com", "example.org", "."] {
+            assert!(!set.contains(&name(v)), "{}", v);
+        }
+
+        let set: DomainSet = [DomainPattern::Suffix(Name::default())].into_iter().collect();
+        assert!(set.contains(&name("example.com")));
+        assert!(set.contains(&Name::default()));
+    }
+
+    #[tokio::test]
+    async fn matcher() {
+        let set: DomainSet = (0..100_000)
+            .map(|i| DomainPattern::Suffix(name(&format!("d{}.example.com", i))))
+            .collect();
+        let m = domain_in(Arc::new(set));
+        assert!(m.matches(&context("www.d99999.example.com")).await.unwrap());
+        assert!(!m.matches(&context("d100000.example.com")).await.unwrap());
+    }
+}
@@ -23,11 +23,7 @@ struct Forward {
 
 impl Forward {
     fn lookup_cache(&self, ctx: &Context) -> Option<Packet> {
-        let cache = if let Some(v) = self.cache.as_ref() {
-            v
-        } else {
-            return None;
-        };
+        let cache = self.cache.as_ref()?;
         let now = Instant::now();
 
         let mut r = ctx.query.to_response();
@@ -157,7 +153,7 @@ impl Forward {
             | RCODE_SERVER_FAILURE
             | RCODE_NX_DOMAIN
             => {
-                let soa = pkt.authorities.get(0)
+                let soa = pkt.authorities.first()
                     .filter(|rr| rr.kind == RRK_SOA && rr.class == pkt.question.class)
                     .cloned();
                 cache.insert(
@@ -184,9 +180,13 @@ impl Action for Forward {
         if !ctx.query.recursion_desired {
             return Ok(ActionResult::Return(Some(ctx.query.to_response_with_code(RCODE_SERVER_FAILURE))));
         }
+        // Only the Internet class is forwarded, the rest are up to the other rules.
+        if ctx.query.question.class != RRC_IN {
+            return Ok(ActionResult::Return(Some(ctx.query.to_response_with_code(RCODE_REFUSED))));
+        }
 
         let sema = if self.cache.is_some() {
-            if let Some(pkt) = self.lookup_cache(&ctx) {
+            if let Some(pkt) = self.lookup_cache(ctx) {
                 return Ok(ActionResult::Return(Some(pkt)));
             }
 
@@ -196,7 +196,7 @@ impl Action for Forward {
             };
             if pending {
                 let _ = sema.acquire().await;
-                if let Some(pkt) = self.lookup_cache(&ctx) {
+                if let Some(pkt) = self.lookup_cache(ctx) {
                     return Ok(ActionResult::Return(Some(pkt)));
                 }
                 None
@@ -211,6 +211,7 @@ impl Action for Forward {
 
         if let Some(pkt) = &mut packet {
             pkt.id = ctx.query.id;
+            pkt.edns = ctx.query.edns.as_ref().map(|v| v.to_response());
             self.update_cache(pkt);
         }
 
@@ -0,0 +1,93 @@
+use async_trait::async_trait;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use regex::RegexSet;
+
+use crate::dns::Name;
+
+use super::*;
+
+struct NameRegex(RegexSet);
+
+#[async_trait]
+impl Matcher for NameRegex {
+    async fn matches(&self, ctx: &Context) -> Result<bool> {
+        Ok(self.0.is_match(&normalized(&ctx.query.question.name)))
+    }
+}
+
+/// Matches queries for the names matching any of the regular expressions. The patterns are
+/// compiled into a single automaton and matched against the normalized name (see `normalized()`),
+/// for example `^ads?[0-9]*\.` matches `ad1.example.com`.
+pub fn name_regex<T: AsRef<str>>(patterns: impl IntoIterator<Item=T>) -> Result<impl Matcher> {
+    Ok(NameRegex(RegexSet::new(patterns)?))
+}
+
+struct NameGlob(GlobSet);
+
+#[async_trait]
+impl Matcher for NameGlob {
+    async fn matches(&self, ctx: &Context) -> Result<bool> {
+        Ok(self.0.is_match(normalized(&ctx.query.question.name)))
+    }
+}
+
+/// Matches queries for the names matching any of the glob patterns against the normalized name
+/// (see `normalized()`). The patterns are case-insensitive and `*` matches across the labels, for
+/// example `*-telemetry.*` matches `eu-telemetry.example.com`.
+pub fn name_glob<T: AsRef<str>>(patterns: impl IntoIterator<Item=T>) -> Result<impl Matcher> {
+    let mut b = GlobSetBuilder::new();
+    for pattern in patterns {
+        b.add(GlobBuilder::new(pattern.as_ref())
+            .case_insensitive(true)
+            .literal_separator(false)
+            .build()?);
+    }
+    Ok(NameGlob(b.build()?))
+}
+
+/// Returns the name in the text form without the trailing dot and lower-cased, for example
+/// `www.example.com`. The root name is `.`.
+pub fn normalized(name: &Name) -> String {
+    let mut r = name.to_string();
+    r.make_ascii_lowercase();
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::process::rule::tests::context;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn regex() {
+        let m = name_regex([r"^ads?[0-9]*\.", r"(^|\.)tracker\.net$"]).unwrap();
+        for (name, expected) in [
+            ("ad.example.com", true),
+            ("ADS12.Example.com", true),
+            ("x.tracker.net", true),
+            ("tracker.net", true),
+            ("bad.example.com", false),
+            ("adserver.example.com", false),
+            ("mytracker.net", false),
+        ] {
+            assert_eq!(m.matches(&context(name)).await.unwrap(), expected, "{}", name);
+        }
+        assert!(name_regex(["("]).is_err());
+    }
+
+    #[tokio::test]
+    async fn glob() {
+        let m = name_glob(["*-telemetry.*", "metrics.*.EXAMPLE.com"]).unwrap();
+        for (name, expected) in [
+            ("eu-telemetry.example.com", true),
+            ("a.b-TELEMETRY.example.com", true),
+            ("metrics.a.b.example.com", true),
+            ("telemetry.example.com", false),
+            ("metrics.example.com", false),
+        ] {
+            assert_eq!(m.matches(&context(name)).await.unwrap(), expected, "{}", name);
+        }
+        assert!(name_glob(["[a-"]).is_err());
+    }
+}
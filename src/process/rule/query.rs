@@ -0,0 +1,122 @@
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+
+use crate::dns::{EOC_CLIENT_SUBNET, Packet, RRClass, RRKind};
+
+use super::*;
+
+struct QueryKind(HashSet<RRKind>);
+
+#[async_trait]
+impl Matcher for QueryKind {
+    async fn matches(&self, ctx: &Context) -> Result<bool> {
+        Ok(self.0.contains(&ctx.query.question.kind))
+    }
+}
+
+/// Matches queries for any of the record types, e.g. `[RRK_HTTPS, RRK_SVCB]`. Query types like
+/// `RRKQ_ALL` are matched literally.
+pub fn query_kind(kinds: impl IntoIterator<Item=RRKind>) -> impl Matcher {
+    QueryKind(kinds.into_iter().collect())
+}
+
+struct QueryClass(HashSet<RRClass>);
+
+#[async_trait]
+impl Matcher for QueryClass {
+    async fn matches(&self, ctx: &Context) -> Result<bool> {
+        Ok(self.0.contains(&ctx.query.question.class))
+    }
+}
+
+/// Matches queries for any of the classes.
+pub fn query_class(classes: impl IntoIterator<Item=RRClass>) -> impl Matcher {
+    QueryClass(classes.into_iter().collect())
+}
+
+struct Flag(fn(&Packet) -> bool);
+
+#[async_trait]
+impl Matcher for Flag {
+    async fn matches(&self, ctx: &Context) -> Result<bool> {
+        Ok(self.0(&ctx.query))
+    }
+}
+
+/// Matches queries with RD bit set.
+pub fn recursion_desired() -> impl Matcher {
+    Flag(|q| q.recursion_desired)
+}
+
+/// Matches queries with CD bit set.
+pub fn checking_disabled() -> impl Matcher {
+    Flag(|q| q.checking_disabled)
+}
+
+/// Matches queries with EDNS DO bit set.
+pub fn dnssec_ok() -> impl Matcher {
+    Flag(|q| q.edns.as_ref().map(|v| v.dnssec_ok).unwrap_or(false))
+}
+
+/// Matches queries with EDNS Client Subnet option (RFC 7871).
+pub fn has_client_subnet() -> impl Matcher {
+    Flag(|q| q.edns.as_ref().and_then(|v| v.option(EOC_CLIENT_SUBNET)).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns::*;
+    use crate::process::rule::tests::context;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn kind_and_class() {
+        let m = query_kind([RRK_HTTPS, RRK_SVCB]);
+        let mut ctx = context("example.com");
+        assert!(!m.matches(&ctx).await.unwrap());
+        ctx.query.question.kind = RRK_SVCB;
+        assert!(m.matches(&ctx).await.unwrap());
+        assert!(!query_kind([RRKQ_ALL]).matches(&ctx).await.unwrap());
+        ctx.query.question.kind = RRKQ_ALL;
+        assert!(query_kind([RRKQ_ALL]).matches(&ctx).await.unwrap());
+
+        let m = query_class([RRC_CH]);
+        assert!(!m.matches(&ctx).await.unwrap());
+        ctx.query.question.class = RRC_CH;
+        assert!(m.matches(&ctx).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn flags() {
+        let mut ctx = context("example.com");
+        assert!(recursion_desired().matches(&ctx).await.unwrap());
+        let ms: [Box<dyn Matcher>; 3] = [
+            Box::new(checking_disabled()),
+            Box::new(dnssec_ok()),
+            Box::new(has_client_subnet()),
+        ];
+        for m in ms {
+            assert!(!m.matches(&ctx).await.unwrap());
+        }
+
+        ctx.query.recursion_desired = false;
+        ctx.query.checking_disabled = true;
+        ctx.query.edns = Some(Edns {
+            dnssec_ok: true,
+            ..Default::default()
+        });
+        assert!(!recursion_desired().matches(&ctx).await.unwrap());
+        assert!(checking_disabled().matches(&ctx).await.unwrap());
+        assert!(dnssec_ok().matches(&ctx).await.unwrap());
+        assert!(!has_client_subnet().matches(&ctx).await.unwrap());
+
+        ctx.query.edns.as_mut().unwrap().options.push(EdnsOption::ClientSubnet(ClientSubnet {
+            addr: "192.0.2.0".parse().unwrap(),
+            source_prefix_len: 24,
+            scope_prefix_len: 0,
+        }));
+        assert!(has_client_subnet().matches(&ctx).await.unwrap());
+    }
+}
@@ -1,98 +1,604 @@
+use std::io;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::time::Duration;
 
-use anyhow::Result;
-use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
-use tokio::sync::mpsc;
-use tracing::{debug, error, info};
+use anyhow::{anyhow, Context as _, Result};
+use socket2::{Domain, Socket, Type};
+use tokio::sync::{mpsc, Mutex, watch};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
 
-use crate::dns::Packet;
+use crate::dns::{DEFAULT_EDNS_UDP_PAYLOAD_SIZE, Packet, RCODE_SERVER_FAILURE};
 use crate::process::Processor;
+use crate::process::rule::{Origin, Transport};
 
-const MAX_UDP_PACKET_LEN: usize = 4096;
+mod https;
+mod quic;
+mod rrl;
+mod tcp;
+mod tls;
+mod udp;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Addresses to listen on for DNS over UDP and TCP. Each address gets its own socket. If the
+    /// port is zero, UDP and TCP get the same port picked by the system.
+    pub addrs: Vec<SocketAddr>,
+    /// Number of UDP sockets bound to each address, each with its own receive loop. Values above 1
+    /// rely on SO_REUSEPORT to spread the load across the sockets.
+    pub udp_sockets_per_addr: usize,
+    /// Max number of UDP queries processed concurrently, across all the UDP sockets.
+    pub max_udp_in_flight: usize,
+    /// What to do with UDP queries received over `max_udp_in_flight`. The REFUSED and SERVFAIL
+    /// responses are subject to `rrl`.
+    pub udp_overload_policy: OverloadPolicy,
+    /// Response Rate Limiting for the UDP responses. Disabled if `None`.
+    pub rrl: Option<RrlConfig>,
+    /// TCP connection with no outstanding queries is closed after this timeout.
+    pub tcp_idle_timeout: Duration,
+    /// New TCP and QUIC connections over this limit are closed immediately. The limit is per
+    /// listener.
+    pub max_tcp_connections: usize,
+    /// Certificate for the encrypted transports.
+    pub tls: Option<TlsConfig>,
+    /// Addresses to listen on for DNS over TLS. Requires `tls`.
+    pub tls_addrs: Vec<SocketAddr>,
+    /// Addresses to listen on for DNS over HTTPS. Requires `tls`.
+    pub https_addrs: Vec<SocketAddr>,
+    /// Addresses to listen on for DNS over QUIC. Requires `tls`.
+    pub quic_addrs: Vec<SocketAddr>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain.
+    pub cert_path: PathBuf,
+    /// PEM file with the private key.
+    pub key_path: PathBuf,
+}
+
+/// Response Rate Limiting (RRL) config. Identical responses sent to a client network are limited
+/// to prevent the server from being used for reflection attacks with spoofed source addresses.
+/// Responses are identical if they answer the same name and type, or are NXDOMAIN for the same
+/// zone, or are errors.
+#[derive(Clone, Debug)]
+pub struct RrlConfig {
+    /// Max number of identical responses per second sent to a client network. Must be positive,
+    /// RRL is disabled by setting `Config::rrl` to `None`.
+    pub responses_per_sec: u32,
+    /// Every `slip`th limited response is sent truncated (TC=1) instead of being dropped, so that
+    /// the legitimate clients retry over TCP. Zero disables slipping.
+    pub slip: u32,
+    /// Period over which the responses are accounted. Client network flooded with responses over
+    /// the limit continues to be limited until this long after the flood stops.
+    pub window: Duration,
+    /// Prefix length of IPv4 client networks.
+    pub ipv4_prefix_len: u8,
+    /// Prefix length of IPv6 client networks.
+    pub ipv6_prefix_len: u8,
+    /// Max number of (client network, response) pairs tracked. When the table is full the least
+    /// recently seen pair is evicted.
+    pub max_entries: usize,
+}
+
+impl Default for RrlConfig {
+    fn default() -> Self {
+        Self {
+            responses_per_sec: 5,
+            slip: 2,
+            window: Duration::from_secs(15),
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 56,
+            max_entries: 100_000,
+        }
+    }
+}
+
+/// Action taken on the queries that can't be processed due to overload.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OverloadPolicy {
+    /// Don't respond.
+    Drop,
+    /// Respond with REFUSED.
+    Refuse,
+    /// Respond with SERVFAIL.
+    ServFail,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            addrs: vec![],
+            udp_sockets_per_addr: 1,
+            max_udp_in_flight: 4096,
+            udp_overload_policy: OverloadPolicy::Drop,
+            rrl: None,
+            tcp_idle_timeout: Duration::from_secs(10),
+            max_tcp_connections: 1000,
+            tls: None,
+            tls_addrs: vec![],
+            https_addrs: vec![],
+            quic_addrs: vec![],
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Server(Arc<Serveri>);
 
 impl Server {
-    pub async fn start(addrs: &[SocketAddr], processor: Processor) -> Result<Self> {
-        let sock = Arc::new(UdpSocket::bind(addrs).await?);
-        info!("listening {:?}", addrs);
-
-        let (stop_tx, mut stop_rx) = mpsc::channel(1);
-        let int = Arc::new(Serveri {
-            receiver: Mutex:: new(Receiver {
-                stop: stop_tx,
-            })
-        });
-        tokio::spawn(async move {
-            let mut buf = [0; MAX_UDP_PACKET_LEN];
-            loop {
-                tokio::select! {
-                    r = sock.recv_from(&mut buf) => {
-                        match r {
-                            Ok((len, src)) => tokio::spawn(clone!(buf, sock, processor => async move {
-                                received(src,
-                                    buf,
-                                    len,
-                                    sock,
-                                    processor).await
-                            })),
-                            Err(err) => {
-                                error!(?err, "error reading from socket");
-                                break;
-                            }
-                        }
-                    }
-                    _ = stop_rx.recv() => {
-                        break;
-                    }
-                };
+    pub async fn start(config: Config, processor: Processor) -> Result<Self> {
+        let (shutdown, tasks) = Shutdown::new();
+        let stats = Arc::new(Stats::default());
+
+        if let Some(rrl) = &config.rrl {
+            if rrl.ipv4_prefix_len > 32 || rrl.ipv6_prefix_len > 128 {
+                return Err(anyhow!("invalid RRL prefix length"));
             }
-        });
-        Ok(Self(int))
+            if rrl.max_entries == 0 {
+                return Err(anyhow!("invalid RRL table size"));
+            }
+            if rrl.responses_per_sec == 0 {
+                return Err(anyhow!("invalid RRL rate"));
+            }
+        }
+        let rrl = config.rrl.clone().map(|v| Arc::new(rrl::Rrl::new(v, stats.clone())));
+        let udp_limit = udp::Limit::new(config.max_udp_in_flight, config.udp_overload_policy,
+            rrl, stats.clone());
+        for &addr in &config.addrs {
+            let (socks, listener) = bind_udp_tcp(addr, config.udp_sockets_per_addr)?;
+            for sock in socks {
+                info!("listening UDP {:?}", sock.local_addr()?);
+                tokio::spawn(udp::serve(sock, processor.clone(), udp_limit.clone(),
+                    tasks.clone()));
+            }
+            info!("listening TCP {:?}", listener.local_addr()?);
+            tokio::spawn(tcp::serve(listener, None, config.clone(), processor.clone(),
+                tasks.clone()));
+        }
+
+        if !config.tls_addrs.is_empty() {
+            let tls = config.tls.as_ref()
+                .ok_or_else(|| anyhow!("TLS config is required for DNS over TLS"))?;
+            let acceptor = TlsAcceptor::from(tls::server_config(tls, &[tls::ALPN_DOT])?);
+            for &addr in &config.tls_addrs {
+                let listener = tcp::bind(addr)
+                    .with_context(|| format!("error binding TLS {}", addr))?;
+                info!("listening TLS {:?}", listener.local_addr()?);
+                tokio::spawn(tcp::serve(listener, Some(acceptor.clone()), config.clone(),
+                    processor.clone(), tasks.clone()));
+            }
+        }
+
+        if !config.https_addrs.is_empty() {
+            let tls = config.tls.as_ref()
+                .ok_or_else(|| anyhow!("TLS config is required for DNS over HTTPS"))?;
+            let acceptor = TlsAcceptor::from(tls::server_config(tls,
+                &[https::ALPN_H2, https::ALPN_HTTP1])?);
+            for &addr in &config.https_addrs {
+                let listener = tcp::bind(addr)
+                    .with_context(|| format!("error binding HTTPS {}", addr))?;
+                info!("listening HTTPS {:?}", listener.local_addr()?);
+                tokio::spawn(https::serve(listener, acceptor.clone(), config.clone(),
+                    processor.clone(), tasks.clone()));
+            }
+        }
+
+        if !config.quic_addrs.is_empty() {
+            let tls = config.tls.as_ref()
+                .ok_or_else(|| anyhow!("TLS config is required for DNS over QUIC"))?;
+            let server_config = quic::server_config(tls, &config)?;
+            for &addr in &config.quic_addrs {
+                let sock = socket(addr, Type::DGRAM)
+                    .and_then(|v| v.bind(&addr.into()).map(|_| v))
+                    .with_context(|| format!("error binding QUIC {}", addr))?;
+                let endpoint = quinn::Endpoint::new(Default::default(),
+                    Some(server_config.clone()), sock.into(), Arc::new(quinn::TokioRuntime))?;
+                info!("listening QUIC {:?}", endpoint.local_addr()?);
+                tokio::spawn(quic::serve(endpoint, config.clone(), processor.clone(),
+                    tasks.clone()));
+            }
+        }
+
+        Ok(Self(Arc::new(Serveri {
+            shutdown: Mutex::new(Some(shutdown)),
+            stats,
+        })))
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.0.stats
+    }
+
+    /// Stops accepting new queries and waits up to `timeout` for the in-flight queries to be
+    /// answered. Idle connections are closed right away. Returns `false` if the timeout has been
+    /// reached.
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        if let Some(shutdown) = self.0.shutdown.lock().await.take() {
+            shutdown.run(timeout).await
+        } else {
+            true
+        }
     }
 }
 
+/// Binds `udp_sockets` UDP sockets and a TCP listener to `addr`. If the port is zero, it's picked
+/// by the system on the first bind and all the rest are bound to the same port.
+fn bind_udp_tcp(
+    addr: SocketAddr,
+    udp_sockets: usize,
+) -> Result<(Vec<udp::UdpSocket>, tokio::net::TcpListener)> {
+    let reuse_port = udp_sockets > 1;
+    let mut bind_addr = addr;
+    let mut socks = Vec::new();
+    for _ in 0..udp_sockets.max(1) {
+        let sock = udp::UdpSocket::bind(bind_addr, reuse_port)
+            .with_context(|| format!("error binding UDP {}", addr))?;
+        bind_addr = sock.local_addr()?;
+        socks.push(sock);
+    }
+    let listener = tcp::bind(bind_addr)
+        .with_context(|| format!("error binding TCP {}", bind_addr))?;
+    Ok((socks, listener))
+}
+
+/// Creates socket for binding to `addr`. IPv6 sockets are made IPv6-only regardless of the system
+/// default, so that IPv4 and IPv6 wildcard addresses can be bound on the same port.
+fn socket(addr: SocketAddr, ty: Type) -> io::Result<Socket> {
+    let r = Socket::new(Domain::for_address(addr), ty, None)?;
+    if addr.is_ipv6() {
+        r.set_only_v6(true)?;
+    }
+    r.set_nonblocking(true)?;
+    Ok(r)
+}
+
 struct Serveri {
-    receiver: Mutex<Receiver>,
+    shutdown: Mutex<Option<Shutdown>>,
+    stats: Arc<Stats>,
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// UDP queries that weren't processed because `Config::max_udp_in_flight` was reached. These
+    /// are dropped or answered according to `Config::udp_overload_policy`.
+    pub udp_queries_shed: AtomicU64,
+    /// UDP responses dropped by RRL.
+    pub rrl_responses_dropped: AtomicU64,
+    /// UDP responses replaced with truncated ones by RRL.
+    pub rrl_responses_slipped: AtomicU64,
+}
+
+struct Shutdown {
+    stop: watch::Sender<bool>,
+    done: mpsc::Receiver<()>,
+}
+
+impl Shutdown {
+    fn new() -> (Self, Tasks) {
+        let (stop_tx, stop_rx) = watch::channel(false);
+        let (done_tx, done_rx) = mpsc::channel(1);
+        (Self {
+            stop: stop_tx,
+            done: done_rx,
+        }, Tasks {
+            stop: stop_rx,
+            _running: done_tx,
+        })
+    }
+
+    async fn run(mut self, timeout: Duration) -> bool {
+        info!("shutting down");
+        let _ = self.stop.send(true);
+        // The channel is closed once all `Tasks` handles are dropped.
+        if tokio::time::timeout(timeout, self.done.recv()).await.is_ok() {
+            info!("shut down");
+            true
+        } else {
+            warn!("shutdown timed out, abandoning in-flight queries");
+            false
+        }
+    }
+}
+
+/// Handle held by each listener and each task serving queries. Shutdown completes when all handles
+/// are dropped.
+#[derive(Clone)]
+struct Tasks {
+    stop: watch::Receiver<bool>,
+    _running: mpsc::Sender<()>,
 }
 
-struct Receiver {
-    stop: mpsc::Sender<()>,
+impl Tasks {
+    /// Completes when the server is shutting down or dropped.
+    async fn stopped(&mut self) {
+        let _ = self.stop.wait_for(|&v| v).await;
+    }
+}
+
+enum Outcome {
+    /// There's no response to send.
+    NoResponse,
+    /// The query couldn't be decoded and is too malformed to respond to.
+    Malformed,
+    /// The query couldn't be decoded, FORMERR response is written.
+    FormatError,
+    /// The response is written.
+    Response(Packet),
 }
 
-#[tracing::instrument(skip_all, fields(?src))]
-async fn received(
-    src: SocketAddr,
-    buf: [u8; MAX_UDP_PACKET_LEN],
-    len: usize,
-    sock: Arc<UdpSocket>,
-    processor: Processor,
-) {
-    debug!(len, "received bytes");
+impl Outcome {
+    fn has_response(&self) -> bool {
+        matches!(self, Self::FormatError | Self::Response(_))
+    }
+}
 
-    match decode_and_process(&buf[..len], processor).await {
-        Ok(Some(resp)) => {
-            let mut buf = Vec::new(); // TODO can use static buf
-            resp.encode(&mut buf);
-            if let Err(err) = sock.send_to(&buf, src).await {
-                error!(?err, "error sending response");
+/// Decodes and processes the query in `msg` and appends the encoded response to `buf`. UDP
+/// response is truncated to fit the client's UDP payload size and is subject to `rrl`. Responses
+/// over the other transports are truncated to the max message length of 65535 bytes.
+async fn handle(
+    msg: &[u8],
+    processor: &Processor,
+    buf: &mut Vec<u8>,
+    origin: Origin,
+    rrl: Option<&rrl::Rrl>,
+) -> Outcome {
+    match Packet::decode(msg) {
+        Ok(query) => {
+            debug!(?query, "decoded");
+            let udp = origin.transport == Transport::Udp;
+            let max_len = if udp {
+                // Don't go over the size we advertise to avoid fragmentation.
+                query.max_udp_payload_size().min(DEFAULT_EDNS_UDP_PAYLOAD_SIZE as usize)
+            } else {
+                // Stream transports prefix messages with a 2-byte length.
+                u16::MAX as usize
+            };
+            match processor.process(query, origin).await {
+                Ok(Some(mut resp)) => {
+                    if let Some(rrl) = rrl.filter(|_| udp) {
+                        match rrl.check(origin.client.ip(), &resp) {
+                            rrl::Verdict::Send => {}
+                            rrl::Verdict::Drop => {
+                                debug!("rate limited, dropping response");
+                                return Outcome::NoResponse;
+                            }
+                            rrl::Verdict::Slip => {
+                                debug!("rate limited, sending truncated response");
+                                resp = rrl::slip(&resp);
+                            }
+                        }
+                    }
+                    if let Err(err) = resp.encode_truncated(buf, max_len) {
+                        error!(?err, ?resp, "error encoding response");
+                        let resp = resp.to_response_with_code(RCODE_SERVER_FAILURE);
+                        resp.encode(buf).expect("error encoding SERVFAIL response");
+                        return Outcome::Response(resp);
+                    }
+                    Outcome::Response(resp)
+                }
+                Ok(None) => {
+                    debug!("no response produced");
+                    Outcome::NoResponse
+                }
+                Err(err) => {
+                    error!(?err);
+                    Outcome::NoResponse
+                }
+            }
+        }
+        Err(err) => {
+            debug!(?err, "error decoding packet");
+            if Packet::encode_format_error(msg, buf) {
+                Outcome::FormatError
             } else {
-                debug!(len = buf.len(), "sent bytes");
+                Outcome::Malformed
             }
         }
-        Ok(None) => debug!("no response produced"),
-        Err(err) => error!(?err),
     }
 }
 
-async fn decode_and_process(msg: &[u8], processor: Processor) -> Result<Option<Packet>> {
-    let query = match Packet::decode(msg) {
-        Ok(v) => v,
-        Err(err) => return Err(err.context("error decoding packet")),
-    };
-    debug!(?query, "decoded");
-    processor.process(query).await
-}
\ No newline at end of file
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
+    use crate::dns::*;
+    use crate::process::rule::*;
+
+    use super::*;
+
+    /// Returns processor answering NXDOMAIN to everything. Names starting with `slow` are answered
+    /// with a delay.
+    pub fn processor() -> Processor {
+        struct Nxdomain;
+
+        #[async_trait]
+        impl Action for Nxdomain {
+            async fn apply(&self, ctx: &mut Context) -> Result<ActionResult> {
+                if ctx.query.question.name.labels().next() == Some(b"slow") {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                }
+                Ok(ActionResult::Return(Some(ctx.query.to_response_with_code(RCODE_NX_DOMAIN))))
+            }
+        }
+
+        let mut rule_lists = HashMap::new();
+        rule_lists.insert(DEFAULT_RULE_LIST_ID.to_owned(), vec![
+            Rule {
+                matcher: Box::new(any()),
+                action: Box::new(Nxdomain),
+            }
+        ]);
+        Processor::new(rule_lists)
+    }
+
+    /// Returns processor answering NXDOMAIN to everything and recording the origins of the queries.
+    pub fn recording_processor() -> (Processor, Arc<parking_lot::Mutex<Vec<Origin>>>) {
+        struct Record(Arc<parking_lot::Mutex<Vec<Origin>>>);
+
+        #[async_trait]
+        impl Action for Record {
+            async fn apply(&self, ctx: &mut Context) -> Result<ActionResult> {
+                self.0.lock().push(ctx.origin);
+                Ok(ActionResult::Return(Some(ctx.query.to_response_with_code(RCODE_NX_DOMAIN))))
+            }
+        }
+
+        let origins = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let mut rule_lists = HashMap::new();
+        rule_lists.insert(DEFAULT_RULE_LIST_ID.to_owned(), vec![
+            Rule {
+                matcher: Box::new(any()),
+                action: Box::new(Record(origins.clone())),
+            }
+        ]);
+        (Processor::new(rule_lists), origins)
+    }
+
+    /// Returns tasks handle of a server that never shuts down.
+    pub(super) fn tasks() -> Tasks {
+        let (shutdown, tasks) = Shutdown::new();
+        std::mem::forget(shutdown);
+        tasks
+    }
+
+    pub fn origin(transport: Transport) -> Origin {
+        Origin {
+            client: "127.0.0.1:1234".parse().unwrap(),
+            local: "127.0.0.1:53".parse().unwrap(),
+            transport,
+        }
+    }
+
+    pub fn query(id: u16, name: &str) -> Vec<u8> {
+        let mut q = Packet::new(id, PacketKind::Query, OP_QUERY, Question {
+            name: name.parse().unwrap(),
+            kind: RRK_A,
+            class: RRC_IN,
+        });
+        q.recursion_desired = true;
+        let mut buf = Vec::new();
+        q.encode(&mut buf).unwrap();
+        buf
+    }
+
+    #[tokio::test]
+    async fn bind_udp_tcp_same_port() {
+        let (socks, listener) = bind_udp_tcp("127.0.0.1:0".parse().unwrap(), 2).unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        assert_eq!(socks.len(), 2);
+        for sock in &socks {
+            assert_eq!(sock.local_addr().unwrap(), addr);
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_rrl_config() {
+        for rrl in [
+            RrlConfig { ipv4_prefix_len: 33, ..Default::default() },
+            RrlConfig { ipv6_prefix_len: 129, ..Default::default() },
+            RrlConfig { max_entries: 0, ..Default::default() },
+            RrlConfig { responses_per_sec: 0, ..Default::default() },
+        ] {
+            let config = Config {
+                rrl: Some(rrl),
+                ..Default::default()
+            };
+            assert!(Server::start(config, processor()).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn handle_format_error() {
+        let mut msg = query(7, "example.com");
+        msg.truncate(msg.len() - 1);
+        let mut buf = Vec::new();
+        let outcome = handle(&msg, &processor(), &mut buf, origin(Transport::Udp), None).await;
+        assert!(matches!(outcome, Outcome::FormatError));
+        assert_eq!(Packet::decode(&buf), Err(Error::BadQuestionCount(0)));
+        assert_eq!(&buf[..4], &[0, 7, 0x81, RCODE_FORMAT_ERROR as u8]);
+
+        // Cookie-only query with no question (RFC 7873 section 5.4).
+        let msg = [
+            0, 8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+            0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 12, 0, 10, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8,
+        ];
+        let mut buf = Vec::new();
+        let outcome = handle(&msg, &processor(), &mut buf, origin(Transport::Udp), None).await;
+        assert!(matches!(outcome, Outcome::FormatError));
+        assert_eq!(&buf[..4], &[0, 8, 0x80, RCODE_FORMAT_ERROR as u8]);
+    }
+
+    #[tokio::test]
+    async fn handle_non_in_class() {
+        let q = Packet::new(1, PacketKind::Query, OP_QUERY, Question {
+            name: "version.bind".parse().unwrap(),
+            kind: RRK_TXT,
+            class: RRC_CH,
+        });
+        let mut msg = Vec::new();
+        q.encode(&mut msg).unwrap();
+        let mut buf = Vec::new();
+        let outcome = handle(&msg, &processor(), &mut buf, origin(Transport::Udp), None).await;
+        assert!(outcome.has_response());
+        let resp = Packet::decode(&buf).unwrap();
+        assert_eq!(resp.question.class, RRC_CH);
+        assert_eq!(resp.response_code, RCODE_NX_DOMAIN);
+    }
+
+    #[tokio::test]
+    async fn handle_context() {
+        struct Tag(&'static str);
+
+        struct Tagger;
+
+        #[async_trait]
+        impl Action for Tagger {
+            async fn apply(&self, ctx: &mut Context) -> Result<ActionResult> {
+                ctx.metadata.insert(Tag("tagged"));
+                Ok(ActionResult::Continue)
+            }
+        }
+
+        struct Reply;
+
+        #[async_trait]
+        impl Action for Reply {
+            async fn apply(&self, ctx: &mut Context) -> Result<ActionResult> {
+                let code = if ctx.origin.transport == Transport::Tls
+                    && ctx.metadata.get::<Tag>().map(|v| v.0) == Some("tagged")
+                {
+                    RCODE_NX_DOMAIN
+                } else {
+                    RCODE_SERVER_FAILURE
+                };
+                Ok(ActionResult::Return(Some(ctx.query.to_response_with_code(code))))
+            }
+        }
+
+        let mut rule_lists = HashMap::new();
+        rule_lists.insert(DEFAULT_RULE_LIST_ID.to_owned(), vec![
+            Rule {
+                matcher: Box::new(any()),
+                action: Box::new(Tagger),
+            },
+            Rule {
+                matcher: Box::new(any()),
+                action: Box::new(Reply),
+            },
+        ]);
+        let processor = Processor::new(rule_lists);
+        let mut buf = Vec::new();
+        let outcome = handle(&query(1, "example.com"), &processor, &mut buf,
+            origin(Transport::Tls), None).await;
+        assert!(outcome.has_response());
+        assert_eq!(Packet::decode(&buf).unwrap().response_code, RCODE_NX_DOMAIN);
+    }
+}
@@ -0,0 +1,432 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64::Engine;
+use base64::engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig};
+use base64::alphabet::URL_SAFE;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use http_body_util::LengthLimitError;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper::body::Incoming;
+use hyper::header::{ALLOW, CACHE_CONTROL, CONTENT_TYPE, HeaderValue};
+use hyper::server::conn::{http1, http2};
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::graceful::GracefulConnection;
+use parking_lot::Mutex;
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
+use tracing::debug;
+
+use crate::dns::{Packet, RRData};
+use crate::process::Processor;
+use crate::process::rule::{Origin, Transport};
+
+use super::{Config, handle, Outcome, Tasks};
+use super::tcp::{accept, tls_accept};
+
+pub const ALPN_H2: &[u8] = b"h2";
+pub const ALPN_HTTP1: &[u8] = b"http/1.1";
+
+/// Path of the DoH endpoint (RFC 8484 section 3).
+const PATH: &str = "/dns-query";
+
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Max length of the DNS message in POST request body.
+const MAX_MSG_LEN: usize = u16::MAX as usize;
+
+/// base64url without padding (RFC 8484 section 4.1). Padded input is tolerated.
+const BASE64: GeneralPurpose = GeneralPurpose::new(&URL_SAFE, GeneralPurposeConfig::new()
+    .with_encode_padding(false)
+    .with_decode_padding_mode(DecodePaddingMode::Indifferent));
+
+/// Requests being served on a connection.
+struct Activity {
+    /// Number of requests in flight and the time the connection was last active.
+    state: Mutex<(usize, Instant)>,
+}
+
+impl Activity {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            state: Mutex::new((0, Instant::now())),
+        })
+    }
+
+    /// Accounts the request until the returned guard is dropped.
+    fn begin(self: &Arc<Self>) -> RequestGuard {
+        self.state.lock().0 += 1;
+        RequestGuard(self.clone())
+    }
+
+    /// Returns the time since which there are no requests in flight, or `None` if there are.
+    fn idle_since(&self) -> Option<Instant> {
+        let (in_flight, last) = *self.state.lock();
+        (in_flight == 0).then_some(last)
+    }
+}
+
+struct RequestGuard(Arc<Activity>);
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock();
+        state.0 -= 1;
+        state.1 = Instant::now();
+    }
+}
+
+/// Accepts TLS connections on `listener` and serves DNS over HTTPS (RFC 8484) on them. HTTP/2 is
+/// used if negotiated via ALPN, HTTP/1.1 otherwise.
+pub async fn serve(
+    listener: TcpListener,
+    tls: TlsAcceptor,
+    config: Config,
+    processor: Processor,
+    mut tasks: Tasks,
+) {
+    let connections = Arc::new(Semaphore::new(config.max_tcp_connections));
+    loop {
+        let (stream, src, local, permit) = tokio::select! {
+            v = accept(&listener, &connections) => v,
+            _ = tasks.stopped() => break,
+        };
+        let idle_timeout = config.tcp_idle_timeout;
+        tokio::spawn(clone!(processor, tls, tasks => async move {
+            let stream = if let Some(v) = tls_accept(&tls, stream, src, idle_timeout).await {
+                v
+            } else {
+                return;
+            };
+            let h2 = stream.get_ref().1.alpn_protocol() == Some(ALPN_H2);
+            let origin = Origin {
+                client: src,
+                local,
+                transport: Transport::Https,
+            };
+            let activity = Activity::new();
+            let service = service_fn({
+                let activity = activity.clone();
+                move |req| {
+                    let guard = activity.begin();
+                    clone!(processor => async move {
+                        let r = respond(req, &processor, origin).await;
+                        drop(guard);
+                        Ok::<_, Infallible>(r)
+                    })
+                }
+            });
+            let r = if h2 {
+                let conn = http2::Builder::new(TokioExecutor::new())
+                    .timer(TokioTimer::new())
+                    .keep_alive_interval(idle_timeout)
+                    .keep_alive_timeout(idle_timeout)
+                    .serve_connection(TokioIo::new(stream), service);
+                serve_connection(conn, &activity, idle_timeout, &mut tasks).await
+            } else {
+                let conn = http1::Builder::new()
+                    .timer(TokioTimer::new())
+                    .header_read_timeout(idle_timeout)
+                    .serve_connection(TokioIo::new(stream), service);
+                serve_connection(conn, &activity, idle_timeout, &mut tasks).await
+            };
+            if let Err(err) = r {
+                debug!(?err, ?src, h2, "error serving HTTP connection");
+            }
+            drop(permit);
+        }));
+    }
+}
+
+/// Drives the HTTP connection. The connection is closed gracefully when it has no requests in
+/// flight for `idle_timeout` and on shutdown: pending requests are answered but no new requests
+/// are accepted.
+async fn serve_connection<C: GracefulConnection>(
+    conn: C,
+    activity: &Activity,
+    idle_timeout: Duration,
+    tasks: &mut Tasks,
+) -> Result<(), C::Error> {
+    let mut conn = std::pin::pin!(conn);
+    let mut idle_deadline = Instant::now() + idle_timeout;
+    loop {
+        tokio::select! {
+            r = conn.as_mut() => return r,
+            _ = tokio::time::sleep_until(idle_deadline) => {
+                match activity.idle_since() {
+                    Some(v) if v + idle_timeout <= Instant::now() => {
+                        debug!("closing idle connection");
+                        conn.as_mut().graceful_shutdown();
+                        return conn.await;
+                    }
+                    Some(v) => idle_deadline = v + idle_timeout,
+                    None => idle_deadline = Instant::now() + idle_timeout,
+                }
+            }
+            _ = tasks.stopped() => {
+                conn.as_mut().graceful_shutdown();
+                return conn.await;
+            }
+        }
+    }
+}
+
+async fn respond(
+    req: Request<Incoming>,
+    processor: &Processor,
+    origin: Origin,
+) -> Response<Full<Bytes>> {
+    if req.uri().path() != PATH {
+        return status(StatusCode::NOT_FOUND);
+    }
+    let msg = match *req.method() {
+        Method::GET => {
+            let msg = req.uri().query()
+                .and_then(|q| q.split('&').find_map(|v| v.strip_prefix("dns=")))
+                .and_then(|v| BASE64.decode(v).ok());
+            if let Some(v) = msg {
+                v
+            } else {
+                return status(StatusCode::BAD_REQUEST);
+            }
+        }
+        Method::POST => {
+            let content_type = req.headers().get(CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok());
+            if !content_type.map(is_dns_message).unwrap_or(false) {
+                return status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            }
+            match Limited::new(req.into_body(), MAX_MSG_LEN).collect().await {
+                Ok(v) => v.to_bytes().to_vec(),
+                Err(err) => {
+                    return status(if err.is::<LengthLimitError>() {
+                        StatusCode::PAYLOAD_TOO_LARGE
+                    } else {
+                        StatusCode::BAD_REQUEST
+                    });
+                }
+            }
+        }
+        _ => {
+            let mut r = status(StatusCode::METHOD_NOT_ALLOWED);
+            r.headers_mut().insert(ALLOW, HeaderValue::from_static("GET, POST"));
+            return r;
+        }
+    };
+    debug!(len = msg.len(), "received bytes");
+
+    let mut buf = Vec::new();
+    let max_age_secs = match handle(&msg, processor, &mut buf, origin, None).await {
+        Outcome::Response(v) => max_age(&v),
+        // DNS errors are successful HTTP responses (RFC 8484 section 4.2.1).
+        Outcome::FormatError => 0,
+        Outcome::Malformed => return status(StatusCode::BAD_REQUEST),
+        Outcome::NoResponse => return status(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    debug!(len = buf.len(), "sent bytes");
+
+    let mut r = Response::new(Full::new(Bytes::from(buf)));
+    r.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(DNS_MESSAGE));
+    let cache_control = format!("max-age={}", max_age_secs);
+    r.headers_mut().insert(CACHE_CONTROL, HeaderValue::try_from(cache_control).unwrap());
+    r
+}
+
+/// Whether the media type without the parameters is `application/dns-message`.
+fn is_dns_message(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default();
+    essence.trim().eq_ignore_ascii_case(DNS_MESSAGE)
+}
+
+fn status(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut r = Response::new(Full::default());
+    *r.status_mut() = status;
+    r
+}
+
+/// Returns freshness lifetime of the response (RFC 8484 section 5.1): the minimum TTL of the
+/// answers or the negative caching TTL from the SOA (RFC 2308 section 5) if there are no answers.
+fn max_age(resp: &Packet) -> u32 {
+    if let Some(v) = resp.answers.iter().map(|rr| rr.ttl_secs).min() {
+        return v;
+    }
+    resp.authorities.iter()
+        .find_map(|rr| match &rr.data {
+            RRData::Soa(soa) => Some(rr.ttl_secs.min(soa.min_ttl_secs)),
+            _ => None,
+        })
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use hyper::client::conn;
+    use tokio::net::TcpStream;
+    use tokio_rustls::client::TlsStream;
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::TlsConnector;
+
+    use crate::dns::*;
+    use crate::server::tests::{processor, query, tasks};
+    use crate::server::tls::{self, tests::{client_config, self_signed}};
+
+    use super::*;
+
+    async fn start(config: Config) -> (SocketAddr, TlsConnector, TlsConnector) {
+        let (tls_config, cert, _dir) = self_signed();
+        let acceptor = TlsAcceptor::from(
+            tls::server_config(&tls_config, &[ALPN_H2, ALPN_HTTP1]).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, acceptor, config, processor(), tasks()));
+        (addr,
+            TlsConnector::from(client_config(cert.clone(), &[ALPN_H2])),
+            TlsConnector::from(client_config(cert, &[ALPN_HTTP1])))
+    }
+
+    async fn connect(addr: SocketAddr, connector: &TlsConnector) -> TokioIo<TlsStream<TcpStream>> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        TokioIo::new(connector.connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await.unwrap())
+    }
+
+    fn post(path: &str, content_type: &str, body: Vec<u8>) -> Request<Full<Bytes>> {
+        Request::post(format!("https://localhost{}", path))
+            .header(CONTENT_TYPE, content_type)
+            .body(Full::new(Bytes::from(body)))
+            .unwrap()
+    }
+
+    fn get(uri: String) -> Request<Full<Bytes>> {
+        Request::get(format!("https://localhost{}", uri))
+            .body(Full::default())
+            .unwrap()
+    }
+
+    async fn body(resp: Response<Incoming>) -> Vec<u8> {
+        resp.into_body().collect().await.unwrap().to_bytes().to_vec()
+    }
+
+    #[tokio::test]
+    async fn h2() {
+        let (addr, h2, _) = start(Config::default()).await;
+        let (mut client, conn) = conn::http2::handshake(TokioExecutor::new(),
+            connect(addr, &h2).await).await.unwrap();
+        tokio::spawn(conn);
+
+        let resp = client.send_request(post(PATH, DNS_MESSAGE, query(0, "example.com")))
+            .await.unwrap();
+        assert_eq!(resp.version(), hyper::Version::HTTP_2);
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], DNS_MESSAGE);
+        assert_eq!(resp.headers()[CACHE_CONTROL], "max-age=0");
+        let resp = Packet::decode(&body(resp).await).unwrap();
+        assert_eq!(resp.response_code, RCODE_NX_DOMAIN);
+
+        let uri = format!("{}?ct&dns={}", PATH, BASE64.encode(query(0, "example.com")));
+        let resp = client.send_request(get(uri)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = Packet::decode(&body(resp).await).unwrap();
+        assert_eq!(resp.question.name, "example.com".parse().unwrap());
+    }
+
+    #[tokio::test]
+    async fn http1() {
+        let (addr, _, http1) = start(Config::default()).await;
+        let (mut client, conn) = conn::http1::handshake(connect(addr, &http1).await).await.unwrap();
+        tokio::spawn(conn);
+
+        let uri = format!("{}?dns={}", PATH, BASE64.encode(query(0, "example.com")));
+        let resp = client.send_request(get(uri)).await.unwrap();
+        assert_eq!(resp.version(), hyper::Version::HTTP_11);
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = Packet::decode(&body(resp).await).unwrap();
+        assert_eq!(resp.response_code, RCODE_NX_DOMAIN);
+
+        for (req, status) in [
+            (get("/foo".into()), StatusCode::NOT_FOUND),
+            (get(PATH.into()), StatusCode::BAD_REQUEST),
+            (get(format!("{}?dns=!!!", PATH)), StatusCode::BAD_REQUEST),
+            (get(format!("{}?dns=AAAA", PATH)), StatusCode::BAD_REQUEST),
+            (post(PATH, "Application/DNS-Message; charset=utf-8", query(0, "example.com")),
+                StatusCode::OK),
+            (post(PATH, "text/plain", query(0, "example.com")), StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            (post(PATH, "application/dns-message-x", query(0, "example.com")),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            (post(PATH, DNS_MESSAGE, vec![0; MAX_MSG_LEN + 1]), StatusCode::PAYLOAD_TOO_LARGE),
+        ] {
+            let resp = client.send_request(req).await.unwrap();
+            assert_eq!(resp.status(), status);
+            body(resp).await;
+        }
+
+        let mut msg = query(0, "example.com");
+        msg.truncate(msg.len() - 1);
+        let resp = client.send_request(post(PATH, DNS_MESSAGE, msg)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = body(resp).await;
+        assert_eq!(resp[3] & 0xf, RCODE_FORMAT_ERROR as u8);
+    }
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        let (addr, h2, http1) = start(Config {
+            tcp_idle_timeout: Duration::from_millis(200),
+            ..Default::default()
+        }).await;
+        let uri = format!("{}?dns={}", PATH, BASE64.encode(query(0, "example.com")));
+
+        let (mut client, conn) = conn::http2::handshake(TokioExecutor::new(),
+            connect(addr, &h2).await).await.unwrap();
+        let conn = tokio::spawn(conn);
+        let resp = client.send_request(get(uri.clone())).await.unwrap();
+        body(resp).await;
+        // Closed by the server.
+        assert!(tokio::time::timeout(Duration::from_secs(2), conn).await.is_ok());
+
+        let (mut client, conn) = conn::http1::handshake(connect(addr, &http1).await).await.unwrap();
+        let conn = tokio::spawn(conn);
+        let resp = client.send_request(get(uri)).await.unwrap();
+        body(resp).await;
+        assert!(tokio::time::timeout(Duration::from_secs(2), conn).await.is_ok());
+    }
+
+    #[test]
+    fn cache_max_age() {
+        let rr = |ttl_secs, data| ResourceRecord {
+            name: "example.com".parse().unwrap(),
+            kind: RRK_A,
+            class: RRC_IN,
+            ttl_secs,
+            data,
+        };
+        let soa = |min_ttl_secs| RRData::Soa(Soa {
+            primary_name: "ns.example.com".parse().unwrap(),
+            responsible_name: "admin.example.com".parse().unwrap(),
+            serial: 1,
+            refresh_secs: 3600,
+            retry_secs: 600,
+            expire_secs: 86400,
+            min_ttl_secs,
+        });
+
+        let mut resp = Packet::decode(&query(0, "example.com")).unwrap().to_response();
+        assert_eq!(max_age(&resp), 0);
+
+        resp.authorities.push(rr(300, soa(60)));
+        assert_eq!(max_age(&resp), 60);
+        resp.authorities[0].ttl_secs = 30;
+        assert_eq!(max_age(&resp), 30);
+
+        resp.answers.push(rr(200, RRData::Ipv4Addr("1.2.3.4".parse().unwrap())));
+        resp.answers.push(rr(100, RRData::Ipv4Addr("1.2.3.5".parse().unwrap())));
+        assert_eq!(max_age(&resp), 100);
+    }
+}
@@ -0,0 +1,306 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use anyhow::Result;
+use quinn::{Connection, ConnectionError, Endpoint, ReadToEndError, RecvStream, SendStream, VarInt};
+use quinn::crypto::rustls::QuicServerConfig;
+use tokio::sync::Semaphore;
+use tracing::{debug, error, warn};
+
+use crate::process::Processor;
+use crate::process::rule::{Origin, Transport};
+
+use super::{Config, handle, Outcome, Tasks, TlsConfig};
+use super::tls;
+
+/// ALPN protocol ID for DNS over QUIC (RFC 9250 section 4.1.1).
+pub const ALPN_DOQ: &[u8] = b"doq";
+
+/// DoQ error code (RFC 9250 section 4.3) used in QUIC CONNECTION_CLOSE and RESET_STREAM frames.
+pub type DoqErrorCode = u32;
+pub const DOQ_NO_ERROR: DoqErrorCode = 0;
+pub const DOQ_INTERNAL_ERROR: DoqErrorCode = 1;
+pub const DOQ_PROTOCOL_ERROR: DoqErrorCode = 2;
+
+/// Max number of query streams the client can have open within a single connection.
+const MAX_STREAMS_PER_CONNECTION: u32 = 100;
+
+const MAX_MSG_LEN: usize = u16::MAX as usize;
+
+/// Creates QUIC server config for DoQ. Clients aren't allowed to open unidirectional streams
+/// (RFC 9250 section 4.2).
+pub fn server_config(tls: &TlsConfig, config: &Config) -> Result<quinn::ServerConfig> {
+    let crypto = QuicServerConfig::try_from(tls::server_config(tls, &[ALPN_DOQ])?)?;
+    let mut r = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    Arc::get_mut(&mut r.transport).unwrap()
+        .max_concurrent_bidi_streams(MAX_STREAMS_PER_CONNECTION.into())
+        .max_concurrent_uni_streams(0u32.into())
+        .max_idle_timeout(Some(config.tcp_idle_timeout.try_into()?));
+    Ok(r)
+}
+
+/// Accepts connections on `endpoint` and serves DNS over QUIC (RFC 9250) on them.
+pub async fn serve(endpoint: Endpoint, config: Config, processor: Processor, mut tasks: Tasks) {
+    let connections = Arc::new(Semaphore::new(config.max_tcp_connections));
+    let local_addr = match endpoint.local_addr() {
+        Ok(v) => v,
+        Err(err) => {
+            error!(?err, "error getting local address");
+            return;
+        }
+    };
+    loop {
+        let incoming = tokio::select! {
+            v = endpoint.accept() => if let Some(v) = v { v } else { break },
+            _ = tasks.stopped() => break,
+        };
+        let src = incoming.remote_address();
+        let permit = if let Ok(v) = connections.clone().try_acquire_owned() {
+            v
+        } else {
+            // Refuse before the handshake so that the excess connections are cheap.
+            warn!(?src, "too many QUIC connections, refusing");
+            incoming.refuse();
+            continue;
+        };
+        tokio::spawn(clone!(processor, tasks => async move {
+            let conn = match incoming.await {
+                Ok(v) => v,
+                Err(err) => {
+                    debug!(?err, ?src, "QUIC handshake failed");
+                    return;
+                }
+            };
+            serve_connection(conn, local_addr, processor, tasks).await;
+            drop(permit);
+        }));
+    }
+    // Let the connections send their CONNECTION_CLOSE frames.
+    endpoint.wait_idle().await;
+}
+
+/// Serves query streams on the connection. On shutdown no more streams are accepted and the
+/// connection is closed with DOQ_NO_ERROR once the outstanding queries are answered.
+#[tracing::instrument(skip_all, fields(src = ?conn.remote_address()))]
+async fn serve_connection(
+    conn: Connection,
+    local_addr: SocketAddr,
+    processor: Processor,
+    mut tasks: Tasks,
+) {
+    debug!("serving connection");
+    let origin = Origin {
+        client: conn.remote_address(),
+        local: SocketAddr::new(conn.local_ip().unwrap_or(local_addr.ip()), local_addr.port()),
+        transport: Transport::Quic,
+    };
+    let in_flight = Arc::new(Semaphore::new(MAX_STREAMS_PER_CONNECTION as usize));
+    loop {
+        let r = tokio::select! {
+            r = conn.accept_bi() => r,
+            _ = tasks.stopped() => {
+                debug!("shutting down connection");
+                let _ = in_flight.acquire_many(MAX_STREAMS_PER_CONNECTION).await;
+                close(&conn, DOQ_NO_ERROR);
+                return;
+            }
+        };
+        let (send, recv) = match r {
+            Ok(v) => v,
+            Err(ConnectionError::ApplicationClosed(v)) => {
+                debug!(code = %v.error_code, "connection closed by client");
+                return;
+            }
+            Err(ConnectionError::TimedOut) => {
+                debug!("closing idle connection");
+                return;
+            }
+            Err(ConnectionError::LocallyClosed) => return,
+            Err(err) => {
+                debug!(?err, "connection error");
+                return;
+            }
+        };
+        let permit = in_flight.clone().acquire_owned().await.unwrap();
+        tokio::spawn(clone!(conn, processor, tasks => async move {
+            if let Err(code) = serve_stream(send, recv, &processor, origin).await {
+                close(&conn, code);
+            }
+            drop(permit);
+            drop(tasks);
+        }));
+    }
+}
+
+/// Serves a single query on the stream. Returns DoQ error code if the connection must be closed
+/// (RFC 9250 section 4.3.3).
+async fn serve_stream(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    processor: &Processor,
+    origin: Origin,
+) -> std::result::Result<(), DoqErrorCode> {
+    // The client indicates the end of the query by closing the stream (RFC 9250 section 4.2).
+    let buf = match recv.read_to_end(2 + MAX_MSG_LEN).await {
+        Ok(v) => v,
+        Err(ReadToEndError::TooLong) => return Err(DOQ_PROTOCOL_ERROR),
+        Err(ReadToEndError::Read(err)) => {
+            debug!(?err, "error reading query");
+            return Ok(());
+        }
+    };
+    let msg = match buf.get(2..) {
+        Some(v) if v.len() == u16::from_be_bytes([buf[0], buf[1]]) as usize => v,
+        _ => return Err(DOQ_PROTOCOL_ERROR),
+    };
+    debug!(len = msg.len(), "received bytes");
+    // Message ID must be 0 (RFC 9250 section 4.2.1).
+    if msg.get(..2) != Some(&[0, 0]) {
+        return Err(DOQ_PROTOCOL_ERROR);
+    }
+
+    let mut resp = vec![0, 0];
+    match handle(msg, processor, &mut resp, origin, None).await {
+        Outcome::Malformed => return Err(DOQ_PROTOCOL_ERROR),
+        Outcome::NoResponse => {
+            let _ = send.reset(DOQ_INTERNAL_ERROR.into());
+            return Ok(());
+        }
+        Outcome::FormatError | Outcome::Response(_) => {}
+    }
+    let len = if let Ok(v) = u16::try_from(resp.len() - 2) {
+        v
+    } else {
+        error!(len = resp.len() - 2, "response is too long");
+        let _ = send.reset(DOQ_INTERNAL_ERROR.into());
+        return Ok(());
+    };
+    resp[..2].copy_from_slice(&len.to_be_bytes());
+    if let Err(err) = send.write_all(&resp).await {
+        debug!(?err, "error writing response");
+        return Ok(());
+    }
+    let _ = send.finish();
+    debug!(len = resp.len(), "sent bytes");
+    Ok(())
+}
+
+fn close(conn: &Connection, code: DoqErrorCode) {
+    debug!(code, "closing connection");
+    conn.close(VarInt::from_u32(code), b"");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use quinn::ApplicationClose;
+    use quinn::crypto::rustls::QuicClientConfig;
+
+    use crate::dns::*;
+    use crate::server::Shutdown;
+    use crate::server::tests::{processor, query, tasks};
+    use crate::server::tls::tests::{client_config, self_signed};
+
+    use super::*;
+
+    /// Starts the server and returns its address and the client endpoint.
+    async fn start(config: Config, tasks: Tasks) -> (SocketAddr, Endpoint) {
+        let (tls_config, cert, _dir) = self_signed();
+        let server_config = server_config(&tls_config, &config).unwrap();
+        let endpoint = Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        tokio::spawn(serve(endpoint, config, processor(), tasks));
+
+        let mut client = Endpoint::client("127.0.0.1:0".parse::<SocketAddr>().unwrap()).unwrap();
+        let crypto = QuicClientConfig::try_from(client_config(cert, &[ALPN_DOQ])).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+        (addr, client)
+    }
+
+    async fn connect() -> Connection {
+        let (addr, client) = start(Config {
+            tcp_idle_timeout: Duration::from_millis(200),
+            ..Default::default()
+        }, tasks()).await;
+        client.connect(addr, "localhost").unwrap().await.unwrap()
+    }
+
+    async fn exchange(conn: &Connection, msg: Vec<u8>) -> Option<Vec<u8>> {
+        let (mut send, mut recv) = conn.open_bi().await.unwrap();
+        send.write_all(&(msg.len() as u16).to_be_bytes()).await.unwrap();
+        send.write_all(&msg).await.unwrap();
+        send.finish().unwrap();
+        recv.read_to_end(2 + MAX_MSG_LEN).await.ok()
+    }
+
+    async fn closed_with(conn: &Connection) -> DoqErrorCode {
+        match conn.closed().await {
+            ConnectionError::ApplicationClosed(ApplicationClose { error_code, .. }) =>
+                error_code.into_inner() as DoqErrorCode,
+            err => panic!("{:?}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn queries() {
+        let conn = connect().await;
+        let (slow, fast) = tokio::join!(
+            exchange(&conn, query(0, "slow.example.com")),
+            exchange(&conn, query(0, "fast.example.com")));
+        for (resp, name) in [(slow, "slow.example.com"), (fast, "fast.example.com")] {
+            let resp = resp.unwrap();
+            assert_eq!(u16::from_be_bytes([resp[0], resp[1]]) as usize, resp.len() - 2);
+            let resp = Packet::decode(&resp[2..]).unwrap();
+            assert_eq!(resp.id, 0);
+            assert_eq!(resp.response_code, RCODE_NX_DOMAIN);
+            assert_eq!(resp.question.name, name.parse().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn nonzero_id() {
+        let conn = connect().await;
+        assert_eq!(exchange(&conn, query(1, "example.com")).await, None);
+        assert_eq!(closed_with(&conn).await, DOQ_PROTOCOL_ERROR);
+    }
+
+    #[tokio::test]
+    async fn bad_length() {
+        let conn = connect().await;
+        let (mut send, mut recv) = conn.open_bi().await.unwrap();
+        send.write_all(&[0, 100, 0, 0]).await.unwrap();
+        send.finish().unwrap();
+        assert!(recv.read_to_end(2 + MAX_MSG_LEN).await.is_err());
+        assert_eq!(closed_with(&conn).await, DOQ_PROTOCOL_ERROR);
+    }
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        let conn = connect().await;
+        assert!(exchange(&conn, query(0, "example.com")).await.is_some());
+        assert!(matches!(conn.closed().await, ConnectionError::TimedOut));
+    }
+
+    #[tokio::test]
+    async fn too_many_connections() {
+        let (addr, client) = start(Config {
+            max_tcp_connections: 1,
+            ..Default::default()
+        }, tasks()).await;
+        let conn = client.connect(addr, "localhost").unwrap().await.unwrap();
+        assert!(exchange(&conn, query(0, "example.com")).await.is_some());
+        let err = client.connect(addr, "localhost").unwrap().await.unwrap_err();
+        assert!(matches!(err, ConnectionError::ConnectionClosed(_)), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        let (shutdown, tasks) = Shutdown::new();
+        let (addr, client) = start(Config::default(), tasks).await;
+        let conn = client.connect(addr, "localhost").unwrap().await.unwrap();
+        assert!(exchange(&conn, query(0, "example.com")).await.is_some());
+        assert!(shutdown.run(Duration::from_secs(2)).await);
+        assert_eq!(closed_with(&conn).await, DOQ_NO_ERROR);
+    }
+}
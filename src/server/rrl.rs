@@ -0,0 +1,333 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tracing::warn;
+
+use crate::dns::{Name, Packet, RCODE_NO_ERROR, RCODE_NX_DOMAIN, RRData, RRKind};
+use crate::lru::LruCache;
+use crate::net::network_addr;
+
+use super::{RrlConfig, Stats};
+
+/// Min interval between the warnings about the table being full.
+const FULL_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Response Rate Limiting. Accounts the responses sent to each client network (address prefix)
+/// separately for each response identity, so that a flood of identical responses to a spoofed
+/// address is cut off while the client's other queries and other clients are unaffected.
+pub struct Rrl {
+    config: RrlConfig,
+    stats: Arc<Stats>,
+    state: Mutex<State>,
+}
+
+/// What to do with the response.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Verdict {
+    Send,
+    Drop,
+    /// Send truncated response instead, so that a legitimate client retries over TCP.
+    Slip,
+}
+
+impl Rrl {
+    pub fn new(config: RrlConfig, stats: Arc<Stats>) -> Self {
+        let entries = LruCache::new(config.max_entries);
+        Self {
+            config,
+            stats,
+            state: Mutex::new(State {
+                entries,
+                last_full_warning: None,
+            }),
+        }
+    }
+
+    /// Accounts the response to be sent to `client`.
+    pub fn check(&self, client: IpAddr, resp: &Packet) -> Verdict {
+        self.check_at(client, resp, Instant::now())
+    }
+
+    fn check_at(&self, client: IpAddr, resp: &Packet, now: Instant) -> Verdict {
+        let key = Key::new(network_addr(client, self.prefix_len(client)), resp);
+        let rate = self.config.responses_per_sec as f64;
+        let mut state = self.state.lock();
+        if !state.entries.contains_key(&key) {
+            if state.entries.len() >= self.config.max_entries {
+                // Entries idle for longer than this have their full credit restored, so evicting
+                // them loses nothing. Evicting the active ones lets their networks through early.
+                let max_idle = self.config.window + Duration::from_secs(1);
+                let evicted = state.entries.pop_lru().unwrap().1;
+                if now.saturating_duration_since(evicted.last) < max_idle
+                    && state.last_full_warning
+                        .map(|v| now.saturating_duration_since(v) >= FULL_WARNING_INTERVAL)
+                        .unwrap_or(true)
+                {
+                    warn!("RRL table is full, evicting active entries");
+                    state.last_full_warning = Some(now);
+                }
+            }
+            state.entries.insert(key.clone(), Entry {
+                balance: rate,
+                last: now,
+                limited: 0,
+            });
+        }
+        let e = state.entries.get_mut(&key).unwrap();
+        let elapsed = now.saturating_duration_since(e.last).as_secs_f64();
+        e.last = now;
+        e.balance = (e.balance + elapsed * rate).min(rate) - 1.0;
+        // Debt is capped so that the network that stops exceeding the rate is let through after
+        // the window passes.
+        e.balance = e.balance.max(-rate * self.config.window.as_secs_f64());
+        if e.balance >= 0.0 {
+            e.limited = 0;
+            return Verdict::Send;
+        }
+        e.limited = e.limited.wrapping_add(1);
+        if self.config.slip > 0 && e.limited.is_multiple_of(self.config.slip) {
+            self.stats.rrl_responses_slipped.fetch_add(1, Ordering::Relaxed);
+            Verdict::Slip
+        } else {
+            self.stats.rrl_responses_dropped.fetch_add(1, Ordering::Relaxed);
+            Verdict::Drop
+        }
+    }
+
+    fn prefix_len(&self, ip: IpAddr) -> u8 {
+        if ip.is_ipv4() {
+            self.config.ipv4_prefix_len
+        } else {
+            self.config.ipv6_prefix_len
+        }
+    }
+}
+
+/// Returns the truncated (TC=1) response with no records to send in place of `resp`.
+pub fn slip(resp: &Packet) -> Packet {
+    let mut r = resp.to_response_with_code(resp.response_code);
+    r.authoritative = resp.authoritative;
+    r.truncated = true;
+    r.recursion_desired = resp.recursion_desired;
+    r.recursion_available = resp.recursion_available;
+    r
+}
+
+struct State {
+    entries: LruCache<Key, Entry>,
+    last_full_warning: Option<Instant>,
+}
+
+#[derive(Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
+struct Key {
+    network: IpAddr,
+    kind: ResponseKind,
+    name: Name,
+    rr_kind: RRKind,
+}
+
+impl Key {
+    fn new(network: IpAddr, resp: &Packet) -> Self {
+        let q = &resp.question;
+        let (kind, name, rr_kind) = match resp.response_code {
+            RCODE_NO_ERROR if !resp.answers.is_empty() =>
+                (ResponseKind::Answer, q.name.clone(), q.kind),
+            RCODE_NO_ERROR => (ResponseKind::NoData, q.name.clone(), q.kind),
+            // Keyed by zone so that random subdomains don't evade the limit.
+            RCODE_NX_DOMAIN => {
+                let zone = resp.authorities.iter()
+                    .find(|rr| matches!(rr.data, RRData::Soa(_)))
+                    .map(|rr| rr.name.clone())
+                    .unwrap_or_else(|| q.name.clone());
+                (ResponseKind::NxDomain, zone, 0)
+            }
+            _ => (ResponseKind::Error, Name::default(), 0),
+        };
+        Self {
+            network,
+            kind,
+            name,
+            rr_kind,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
+enum ResponseKind {
+    Answer,
+    NoData,
+    NxDomain,
+    Error,
+}
+
+struct Entry {
+    /// Responses that can be sent right away. Negative when over the rate.
+    balance: f64,
+    last: Instant,
+    /// Responses limited since the balance went negative, for counting slips.
+    limited: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::dns::*;
+
+    use super::*;
+
+    fn rrl(config: RrlConfig) -> Rrl {
+        Rrl::new(config, Arc::new(Stats::default()))
+    }
+
+    fn resp(name: &str, response_code: ResponseCode) -> Packet {
+        let mut r = Packet::new(1, PacketKind::Response, OP_QUERY, Question {
+            name: name.parse().unwrap(),
+            kind: RRK_A,
+            class: RRC_IN,
+        });
+        r.response_code = response_code;
+        if response_code == RCODE_NO_ERROR {
+            r.answers.push(ResourceRecord {
+                name: r.question.name.clone(),
+                kind: RRK_A,
+                class: RRC_IN,
+                ttl_secs: 60,
+                data: RRData::Ipv4Addr(Ipv4Addr::new(1, 2, 3, 4)),
+            });
+        }
+        r
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn keys() {
+        let rrl = rrl(RrlConfig {
+            responses_per_sec: 2,
+            slip: 0,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let a = resp("a.example.com", RCODE_NO_ERROR);
+        for _ in 0..2 {
+            assert_eq!(rrl.check_at(ip("10.0.0.1"), &a, now), Verdict::Send);
+        }
+        // Same network.
+        assert_eq!(rrl.check_at(ip("10.0.0.2"), &a, now), Verdict::Drop);
+        assert_eq!(rrl.check_at(ip("2001:db8:0:ff::1"), &a, now), Verdict::Send);
+        assert_eq!(rrl.check_at(ip("2001:db8:0:ff::1"), &a, now), Verdict::Send);
+        assert_eq!(rrl.check_at(ip("2001:db8:0:aa::2"), &a, now), Verdict::Drop);
+
+        // Other networks and other responses.
+        assert_eq!(rrl.check_at(ip("10.0.1.1"), &a, now), Verdict::Send);
+        assert_eq!(rrl.check_at(ip("2001:db8:0:100::1"), &a, now), Verdict::Send);
+        assert_eq!(rrl.check_at(ip("10.0.0.1"), &resp("b.example.com", RCODE_NO_ERROR), now),
+            Verdict::Send);
+
+        // NXDOMAIN responses are keyed by zone.
+        let nxdomain = |name| {
+            let mut r = resp(name, RCODE_NX_DOMAIN);
+            r.authorities.push(ResourceRecord {
+                name: "example.com".parse().unwrap(),
+                kind: RRK_SOA,
+                class: RRC_IN,
+                ttl_secs: 60,
+                data: RRData::Soa(Soa {
+                    primary_name: "ns.example.com".parse().unwrap(),
+                    responsible_name: "admin.example.com".parse().unwrap(),
+                    serial: 1,
+                    refresh_secs: 1,
+                    retry_secs: 1,
+                    expire_secs: 1,
+                    min_ttl_secs: 1,
+                }),
+            });
+            r
+        };
+        assert_eq!(rrl.check_at(ip("10.0.0.1"), &nxdomain("x.example.com"), now), Verdict::Send);
+        assert_eq!(rrl.check_at(ip("10.0.0.1"), &nxdomain("y.example.com"), now), Verdict::Send);
+        assert_eq!(rrl.check_at(ip("10.0.0.1"), &nxdomain("z.example.com"), now), Verdict::Drop);
+    }
+
+    #[test]
+    fn rate_and_window() {
+        let rrl = rrl(RrlConfig {
+            responses_per_sec: 2,
+            slip: 0,
+            window: Duration::from_secs(5),
+            ..Default::default()
+        });
+        let client = ip("10.0.0.1");
+        let a = resp("a.example.com", RCODE_NO_ERROR);
+        let mut now = Instant::now();
+        for _ in 0..2 {
+            assert_eq!(rrl.check_at(client, &a, now), Verdict::Send);
+        }
+        assert_eq!(rrl.check_at(client, &a, now), Verdict::Drop);
+        // Limited responses count towards the rate too.
+        now += Duration::from_secs(1);
+        assert_eq!(rrl.check_at(client, &a, now), Verdict::Send);
+        assert_eq!(rrl.check_at(client, &a, now), Verdict::Drop);
+
+        // Flood builds up debt up to the window.
+        for _ in 0..1000 {
+            assert_eq!(rrl.check_at(client, &a, now), Verdict::Drop);
+        }
+        now += Duration::from_secs(4);
+        assert_eq!(rrl.check_at(client, &a, now), Verdict::Drop);
+        now += Duration::from_secs(2);
+        assert_eq!(rrl.check_at(client, &a, now), Verdict::Send);
+    }
+
+    #[test]
+    fn slip() {
+        let rrl = rrl(RrlConfig {
+            responses_per_sec: 1,
+            slip: 2,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let a = resp("a.example.com", RCODE_NO_ERROR);
+        assert_eq!(rrl.check_at(ip("10.0.0.1"), &a, now), Verdict::Send);
+        for _ in 0..2 {
+            assert_eq!(rrl.check_at(ip("10.0.0.1"), &a, now), Verdict::Drop);
+            assert_eq!(rrl.check_at(ip("10.0.0.1"), &a, now), Verdict::Slip);
+        }
+        assert_eq!(rrl.stats.rrl_responses_dropped.load(Ordering::Relaxed), 2);
+        assert_eq!(rrl.stats.rrl_responses_slipped.load(Ordering::Relaxed), 2);
+
+        let slip = super::slip(&a);
+        assert!(slip.truncated);
+        assert!(slip.answers.is_empty());
+        assert_eq!(slip.question, a.question);
+    }
+
+    #[test]
+    fn full_table() {
+        let rrl = rrl(RrlConfig {
+            responses_per_sec: 1,
+            slip: 0,
+            max_entries: 2,
+            ..Default::default()
+        });
+        let now = Instant::now();
+        let a = resp("a.example.com", RCODE_NO_ERROR);
+        for client in ["10.0.0.1", "10.0.1.1"] {
+            assert_eq!(rrl.check_at(ip(client), &a, now), Verdict::Send);
+        }
+        assert_eq!(rrl.check_at(ip("10.0.1.1"), &a, now), Verdict::Drop);
+        assert_eq!(rrl.check_at(ip("10.0.0.1"), &a, now), Verdict::Drop);
+
+        // The least recently seen entry is evicted.
+        assert_eq!(rrl.check_at(ip("10.0.2.1"), &a, now), Verdict::Send);
+        assert_eq!(rrl.check_at(ip("10.0.0.1"), &a, now), Verdict::Drop);
+        assert_eq!(rrl.check_at(ip("10.0.1.1"), &a, now), Verdict::Send);
+        assert!(rrl.state.lock().last_full_warning.is_some());
+    }
+}
@@ -0,0 +1,407 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::{Buf, BytesMut};
+use socket2::Type;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Instant, timeout};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, warn};
+
+use crate::process::Processor;
+use crate::process::rule::{Origin, Transport};
+
+use super::{Config, handle, socket, Tasks};
+
+/// Max number of queries processed concurrently within a single connection. When reached, no more
+/// queries are read from the connection until some of the responses are sent.
+const MAX_IN_FLIGHT_PER_CONNECTION: usize = 32;
+
+const LISTEN_BACKLOG: i32 = 1024;
+
+/// Delay before retrying after an accept error, doubled on each consecutive error up to
+/// `MAX_ACCEPT_ERROR_DELAY`. Errors like EMFILE persist until some connections are closed.
+const MIN_ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+const MAX_ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+pub fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let sock = socket(addr, Type::STREAM)?;
+    sock.set_reuse_address(true)?;
+    sock.bind(&addr.into())?;
+    sock.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(sock.into())
+}
+
+/// Accepts connections on `listener` and serves them. If `tls` is specified, the connections are
+/// served over TLS (RFC 7858).
+pub async fn serve(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    config: Config,
+    processor: Processor,
+    mut tasks: Tasks,
+) {
+    let connections = Arc::new(Semaphore::new(config.max_tcp_connections));
+    loop {
+        let (stream, src, local, permit) = tokio::select! {
+            v = accept(&listener, &connections) => v,
+            _ = tasks.stopped() => break,
+        };
+        let idle_timeout = config.tcp_idle_timeout;
+        tokio::spawn(clone!(processor, tls, tasks => async move {
+            if let Some(tls) = tls {
+                let stream = if let Some(v) = tls_accept(&tls, stream, src, idle_timeout).await {
+                    v
+                } else {
+                    return;
+                };
+                let origin = Origin {
+                    client: src,
+                    local,
+                    transport: Transport::Tls,
+                };
+                serve_connection(stream, origin, processor, idle_timeout, tasks).await;
+            } else {
+                let origin = Origin {
+                    client: src,
+                    local,
+                    transport: Transport::Tcp,
+                };
+                serve_connection(stream, origin, processor, idle_timeout, tasks).await;
+            }
+            drop(permit);
+        }));
+    }
+}
+
+/// Accepts the next connection on `listener`. Returns the stream, the remote and the local
+/// addresses. Connections over the `connections` limit are closed right away. Accept errors are
+/// retried after a delay.
+pub async fn accept(
+    listener: &TcpListener,
+    connections: &Arc<Semaphore>,
+) -> (TcpStream, SocketAddr, SocketAddr, OwnedSemaphorePermit) {
+    let mut error_delay = MIN_ACCEPT_ERROR_DELAY;
+    loop {
+        let (stream, src) = match listener.accept().await {
+            Ok(v) => v,
+            Err(err) => {
+                error!(?err, "error accepting TCP connection");
+                tokio::time::sleep(error_delay).await;
+                error_delay = (error_delay * 2).min(MAX_ACCEPT_ERROR_DELAY);
+                continue;
+            }
+        };
+        error_delay = MIN_ACCEPT_ERROR_DELAY;
+        let local = match stream.local_addr() {
+            Ok(v) => v,
+            Err(err) => {
+                debug!(?err, ?src, "error getting local address");
+                continue;
+            }
+        };
+        if let Ok(permit) = connections.clone().try_acquire_owned() {
+            return (stream, src, local, permit);
+        }
+        warn!(?src, "too many TCP connections, closing");
+    }
+}
+
+/// Performs the server side of TLS handshake. Returns `None` if the handshake fails or doesn't
+/// complete within `timeout_dur`.
+pub async fn tls_accept(
+    tls: &TlsAcceptor,
+    stream: TcpStream,
+    src: SocketAddr,
+    timeout_dur: Duration,
+) -> Option<TlsStream<TcpStream>> {
+    match timeout(timeout_dur, tls.accept(stream)).await {
+        Ok(Ok(v)) => Some(v),
+        Ok(Err(err)) => {
+            debug!(?err, ?src, "TLS handshake failed");
+            None
+        }
+        Err(_) => {
+            debug!(?src, "TLS handshake timed out");
+            None
+        }
+    }
+}
+
+/// Serves length-prefixed queries (RFC 1035 4.2.2) on a stream until it's closed by the client or
+/// stays idle for `idle_timeout`. Queries are processed concurrently and the responses are sent
+/// as soon as they're ready, possibly out of order (RFC 7766 6.2.1.1). On shutdown no more queries
+/// are read and the connection is closed after the outstanding responses are sent.
+#[tracing::instrument(skip_all, fields(src = ?origin.client))]
+pub async fn serve_connection<S>(
+    stream: S,
+    origin: Origin,
+    processor: Processor,
+    idle_timeout: Duration,
+    mut tasks: Tasks,
+)
+    where S: AsyncRead + AsyncWrite + Send + 'static
+{
+    debug!("serving connection");
+
+    let (mut rd, mut wr) = tokio::io::split(stream);
+
+    let (resp_tx, mut resp_rx) = mpsc::channel::<Vec<u8>>(MAX_IN_FLIGHT_PER_CONNECTION);
+    let writer = tokio::spawn(async move {
+        while let Some(buf) = resp_rx.recv().await {
+            if let Err(err) = wr.write_all(&buf).await {
+                debug!(?err, "error writing to connection");
+                return;
+            }
+            debug!(len = buf.len(), "sent bytes");
+        }
+        let _ = wr.shutdown().await;
+    });
+
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT_PER_CONNECTION));
+    let mut buf = BytesMut::with_capacity(2 + 512);
+    let mut idle_deadline = Instant::now() + idle_timeout;
+    'outer: loop {
+        while let Some(msg) = next_frame(&mut buf) {
+            debug!(len = msg.len(), "received bytes");
+            let permit = in_flight.clone().acquire_owned().await.unwrap();
+            tokio::spawn(clone!(processor, resp_tx => async move {
+                let mut resp = vec![0, 0];
+                if handle(&msg, &processor, &mut resp, origin, None).await.has_response() {
+                    if let Ok(len) = u16::try_from(resp.len() - 2) {
+                        resp[..2].copy_from_slice(&len.to_be_bytes());
+                        let _ = resp_tx.send(resp).await;
+                    } else {
+                        error!(len = resp.len() - 2, "response is too long");
+                    }
+                }
+                drop(permit);
+            }));
+        }
+
+        tokio::select! {
+            r = rd.read_buf(&mut buf) => {
+                match r {
+                    Ok(0) => {
+                        debug!("connection closed by client");
+                        break 'outer;
+                    }
+                    Ok(_) => idle_deadline = Instant::now() + idle_timeout,
+                    Err(err) => {
+                        debug!(?err, "error reading from connection");
+                        break 'outer;
+                    }
+                }
+            }
+            _ = tokio::time::sleep_until(idle_deadline) => {
+                if in_flight.available_permits() == MAX_IN_FLIGHT_PER_CONNECTION {
+                    debug!("closing idle connection");
+                    break 'outer;
+                }
+                idle_deadline = Instant::now() + idle_timeout;
+            }
+            _ = tasks.stopped() => {
+                debug!("shutting down connection");
+                break 'outer;
+            }
+        }
+    }
+
+    // Let the outstanding queries finish, the writer stops once all the senders are dropped.
+    drop(resp_tx);
+    let _ = writer.await;
+}
+
+/// Extracts the next complete length-prefixed message from `buf`.
+fn next_frame(buf: &mut BytesMut) -> Option<BytesMut> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+    if buf.len() < 2 + len {
+        buf.reserve(2 + len - buf.len());
+        return None;
+    }
+    buf.advance(2);
+    Some(buf.split_to(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::DuplexStream;
+
+    use crate::dns::*;
+    use crate::server::Shutdown;
+    use crate::server::tests::{origin, processor, query, tasks};
+
+    use super::*;
+
+    fn frame(msg: &[u8]) -> Vec<u8> {
+        let mut r = (msg.len() as u16).to_be_bytes().to_vec();
+        r.extend_from_slice(msg);
+        r
+    }
+
+    async fn read_response(client: &mut DuplexStream) -> Packet {
+        let len = client.read_u16().await.unwrap();
+        let mut buf = vec![0; len as usize];
+        client.read_exact(&mut buf).await.unwrap();
+        Packet::decode(&buf).unwrap()
+    }
+
+    fn start(idle_timeout: Duration) -> DuplexStream {
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve_connection(server, origin(Transport::Tcp), processor(), idle_timeout,
+            tasks()));
+        client
+    }
+
+    #[tokio::test]
+    async fn pipelined_out_of_order() {
+        let mut client = start(Duration::from_secs(10));
+
+        let mut buf = frame(&query(1, "slow.example.com"));
+        buf.extend(frame(&query(2, "fast.example.com")));
+        // Split a message across writes.
+        let tail = buf.split_off(buf.len() - 5);
+        client.write_all(&buf).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        client.write_all(&tail).await.unwrap();
+
+        let resp = read_response(&mut client).await;
+        assert_eq!(resp.id, 2);
+        assert_eq!(resp.response_code, RCODE_NX_DOMAIN);
+        assert_eq!(read_response(&mut client).await.id, 1);
+    }
+
+    #[tokio::test]
+    async fn responses_sent_after_client_eof() {
+        let mut client = start(Duration::from_secs(10));
+        client.write_all(&frame(&query(1, "slow.example.com"))).await.unwrap();
+        client.shutdown().await.unwrap();
+        assert_eq!(read_response(&mut client).await.id, 1);
+        assert_eq!(client.read_u8().await.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn truncate_long_response() {
+        use std::collections::HashMap;
+
+        use async_trait::async_trait;
+
+        use crate::process::rule::*;
+
+        /// Answers with 300 TXT RRsets of about 270 bytes each.
+        struct Long;
+
+        #[async_trait]
+        impl Action for Long {
+            async fn apply(&self, ctx: &mut Context) -> anyhow::Result<ActionResult> {
+                let mut resp = ctx.query.to_response();
+                for i in 0..300 {
+                    resp.answers.push(ResourceRecord {
+                        name: format!("{}.example.com", i).parse().unwrap(),
+                        kind: RRK_TXT,
+                        class: RRC_IN,
+                        ttl_secs: 60,
+                        data: RRData::Txt(vec![vec![b'x'; 255]]),
+                    });
+                }
+                Ok(ActionResult::Return(Some(resp)))
+            }
+        }
+
+        let mut rule_lists = HashMap::new();
+        rule_lists.insert(DEFAULT_RULE_LIST_ID.to_owned(), vec![
+            Rule {
+                matcher: Box::new(any()),
+                action: Box::new(Long),
+            }
+        ]);
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve_connection(server, origin(Transport::Tcp), Processor::new(rule_lists),
+            Duration::from_secs(10), tasks()));
+
+        client.write_all(&frame(&query(1, "example.com"))).await.unwrap();
+        let resp = read_response(&mut client).await;
+        assert_eq!(resp.id, 1);
+        assert!(resp.truncated);
+        assert!(!resp.answers.is_empty() && resp.answers.len() < 300);
+    }
+
+    #[tokio::test]
+    async fn tls() {
+        use tokio_rustls::rustls::HandshakeKind;
+        use tokio_rustls::rustls::pki_types::ServerName;
+        use tokio_rustls::TlsConnector;
+
+        use crate::server::tls::{self, ALPN_DOT};
+        use crate::server::tls::tests::{client_config, self_signed};
+
+        let (tls_config, cert, _dir) = self_signed();
+        let acceptor = TlsAcceptor::from(tls::server_config(&tls_config, &[ALPN_DOT]).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, Some(acceptor), Config::default(), processor(), tasks()));
+
+        let connector = TlsConnector::from(client_config(cert, &[ALPN_DOT]));
+        for (id, handshake_kind) in [(1, HandshakeKind::Full), (2, HandshakeKind::Resumed)] {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut stream = connector.connect(ServerName::try_from("localhost").unwrap(), stream)
+                .await.unwrap();
+            let (_, conn) = stream.get_ref();
+            assert_eq!(conn.alpn_protocol(), Some(ALPN_DOT));
+            assert_eq!(conn.handshake_kind(), Some(handshake_kind));
+
+            stream.write_all(&frame(&query(id, "example.com"))).await.unwrap();
+            let len = stream.read_u16().await.unwrap();
+            let mut buf = vec![0; len as usize];
+            stream.read_exact(&mut buf).await.unwrap();
+            let resp = Packet::decode(&buf).unwrap();
+            assert_eq!(resp.id, id);
+            assert_eq!(resp.response_code, RCODE_NX_DOMAIN);
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        let (shutdown, tasks) = Shutdown::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, None, Config::default(), processor(), tasks));
+
+        let mut busy = TcpStream::connect(addr).await.unwrap();
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        busy.write_all(&frame(&query(1, "slow.example.com"))).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let shutdown = tokio::spawn(shutdown.run(Duration::from_secs(1)));
+        assert_eq!(idle.read_u8().await.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        let len = busy.read_u16().await.unwrap();
+        let mut buf = vec![0; len as usize];
+        busy.read_exact(&mut buf).await.unwrap();
+        assert_eq!(Packet::decode(&buf).unwrap().id, 1);
+        assert_eq!(busy.read_u8().await.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(shutdown.await.unwrap());
+
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        let mut client = start(Duration::from_millis(50));
+
+        // Outstanding query keeps the connection open.
+        client.write_all(&frame(&query(1, "slow.example.com"))).await.unwrap();
+        assert_eq!(read_response(&mut client).await.id, 1);
+
+        let start = Instant::now();
+        assert_eq!(client.read_u8().await.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
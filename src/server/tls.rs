@@ -0,0 +1,123 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::server::ServerSessionMemoryCache;
+use tokio_rustls::rustls::ServerConfig;
+
+use super::TlsConfig;
+
+/// ALPN protocol ID for DNS over TLS (RFC 7858 section 3.2).
+pub const ALPN_DOT: &[u8] = b"dot";
+
+const SESSION_CACHE_SIZE: usize = 1024;
+
+/// Creates rustls server config with the certificate from `config` and the specified ALPN
+/// protocols. Both stateful and stateless (ticket based) session resumption are enabled.
+pub fn server_config(config: &TlsConfig, alpn: &[&[u8]]) -> Result<Arc<ServerConfig>> {
+    let certs = rustls_pemfile::certs(&mut open(&config.cert_path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("error reading certificates from {:?}", config.cert_path))?;
+    if certs.is_empty() {
+        return Err(anyhow!("no certificates found in {:?}", config.cert_path));
+    }
+    let key = rustls_pemfile::private_key(&mut open(&config.key_path)?)
+        .with_context(|| format!("error reading private key from {:?}", config.key_path))?
+        .ok_or_else(|| anyhow!("no private key found in {:?}", config.key_path))?;
+
+    let mut r = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    r.alpn_protocols = alpn.iter().map(|v| v.to_vec()).collect();
+    r.session_storage = ServerSessionMemoryCache::new(SESSION_CACHE_SIZE);
+    r.ticketer = ring::Ticketer::new()?;
+    Ok(Arc::new(r))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    Ok(BufReader::new(File::open(path)
+        .with_context(|| format!("error opening {:?}", path))?))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::rustls::pki_types::CertificateDer;
+
+    use super::*;
+
+    /// Directory that is removed with its contents on drop.
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub fn new() -> Self {
+            static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+            let path = std::env::temp_dir().join(format!("mudns-test-{}-{}",
+                std::process::id(), NEXT_ID.fetch_add(1, Ordering::Relaxed)));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Generates self-signed certificate for `localhost` and writes it and the key into temp files.
+    /// Returns the config, the certificate and the directory holding the files, which must be kept
+    /// alive while the files are in use.
+    pub fn self_signed() -> (TlsConfig, CertificateDer<'static>, TempDir) {
+        let key = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+
+        let dir = TempDir::new();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, key.cert.pem()).unwrap();
+        std::fs::write(&key_path, key.key_pair.serialize_pem()).unwrap();
+
+        (TlsConfig { cert_path, key_path }, key.cert.der().clone(), dir)
+    }
+
+    pub fn client_config(cert: CertificateDer<'static>, alpn: &[&[u8]]) -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let mut r = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions().unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        r.alpn_protocols = alpn.iter().map(|v| v.to_vec()).collect();
+        Arc::new(r)
+    }
+
+    #[test]
+    fn bad_files() {
+        let (config, _, _dir) = self_signed();
+        assert!(server_config(&config, &[ALPN_DOT]).is_ok());
+
+        let bad = TlsConfig {
+            cert_path: PathBuf::from("/nonexistent"),
+            ..config.clone()
+        };
+        assert!(server_config(&bad, &[ALPN_DOT]).is_err());
+
+        let bad = TlsConfig {
+            key_path: config.cert_path.clone(),
+            ..config
+        };
+        assert!(server_config(&bad, &[ALPN_DOT]).is_err());
+    }
+}
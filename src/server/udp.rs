@@ -0,0 +1,607 @@
+use std::io::{self, IoSliceMut};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use parking_lot::Mutex;
+use quinn_udp::{BATCH_SIZE, RecvMeta, Transmit, UdpSocketState};
+use socket2::Type;
+use tokio::io::Interest;
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{debug, error};
+
+use crate::dns::{Packet, PacketKind, RCODE_REFUSED, RCODE_SERVER_FAILURE};
+use crate::process::Processor;
+use crate::process::rule::{Origin, Transport};
+
+use super::{handle, OverloadPolicy, socket, Stats, Tasks};
+use super::rrl::{self, Rrl, Verdict};
+
+const MAX_UDP_PACKET_LEN: usize = 4096;
+
+/// Max number of responses waiting to be sent on a socket.
+const SEND_QUEUE_LEN: usize = 1024;
+
+/// Max number of idle buffers kept in a socket's buffer pool.
+const MAX_POOLED_BUFS: usize = 1024;
+
+/// UDP socket that reports the destination address of the received datagrams (IP_PKTINFO,
+/// IPV6_RECVPKTINFO) so that the responses can be sent from the same address, which matters when
+/// bound to a wildcard address on a multihomed host. On Linux datagrams are received and sent in
+/// batches with `recvmmsg` and `sendmmsg`.
+pub struct UdpSocket {
+    io: tokio::net::UdpSocket,
+    state: UdpSocketState,
+}
+
+impl UdpSocket {
+    /// Binds socket to `addr`. If `reuse_port` is `true`, SO_REUSEPORT is set so that multiple
+    /// sockets can be bound to the same address and the kernel distributes datagrams among them.
+    pub fn bind(addr: SocketAddr, reuse_port: bool) -> io::Result<Self> {
+        let sock = socket(addr, Type::DGRAM)?;
+        if reuse_port {
+            #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+            sock.set_reuse_port(true)?;
+            #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "SO_REUSEPORT is not supported"));
+        }
+        sock.bind(&addr.into())?;
+        let state = UdpSocketState::new((&sock).into())?;
+        // Coalesced datagrams could be truncated since the buffers are sized for a single one.
+        #[cfg(target_os = "linux")]
+        nix::sys::socket::setsockopt(&sock, nix::sys::socket::sockopt::UdpGroSegment, &false)?;
+        Ok(Self {
+            io: tokio::net::UdpSocket::from_std(sock.into())?,
+            state,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.io.local_addr()
+    }
+
+    /// Receives datagrams into `bufs`, one datagram per buffer. Returns the number of the received
+    /// datagrams, with the metadata in the corresponding `meta` items. At most `BATCH_SIZE`
+    /// buffers are used.
+    pub async fn recv(&self, bufs: &mut [Vec<u8>], meta: &mut [RecvMeta]) -> io::Result<usize> {
+        let len = bufs.len().min(BATCH_SIZE);
+        self.io.async_io(Interest::READABLE, || {
+            let mut bufs = bufs.iter_mut();
+            let mut slices: [_; BATCH_SIZE] = std::array::from_fn(|_| {
+                IoSliceMut::new(bufs.next().map(|v| &mut v[..]).unwrap_or_default())
+            });
+            self.state.recv((&self.io).into(), &mut slices[..len], meta)
+        }).await
+    }
+
+    /// Sends `buf` to `dst` from the local address `src_ip`, if specified.
+    pub async fn send(
+        &self,
+        buf: &[u8],
+        dst: SocketAddr,
+        src_ip: Option<IpAddr>,
+    ) -> io::Result<()> {
+        let transmit = Transmit {
+            destination: dst,
+            ecn: None,
+            contents: buf,
+            segment_size: None,
+            src_ip,
+        };
+        self.io.async_io(Interest::WRITABLE, || {
+            self.state.try_send((&self.io).into(), &transmit)
+        }).await
+    }
+
+    /// Sends the datagrams, logging the errors.
+    #[cfg(target_os = "linux")]
+    async fn send_batch(&self, mut batch: &[Outgoing]) {
+        while !batch.is_empty() {
+            // Control messages are shared by all the messages in a single `sendmmsg` call.
+            let len = batch.iter().take_while(|v| v.src_ip == batch[0].src_ip).count();
+            match self.io.async_io(Interest::WRITABLE, || sendmmsg(&self.io, &batch[..len])).await {
+                Ok(sent) => batch = &batch[sent..],
+                Err(err) => {
+                    error!(?err, dst = ?batch[0].dst, "error sending response");
+                    batch = &batch[1..];
+                }
+            }
+        }
+    }
+
+    /// Sends the datagrams, logging the errors.
+    #[cfg(not(target_os = "linux"))]
+    async fn send_batch(&self, batch: &[Outgoing]) {
+        for v in batch {
+            if let Err(err) = self.send(&v.buf, v.dst, v.src_ip).await {
+                error!(?err, dst = ?v.dst, "error sending response");
+            }
+        }
+    }
+}
+
+/// Buffers reused by `sendmmsg` calls made on the same thread. They can't be owned by the send
+/// loop since `MultiHeaders` is not `Send`.
+#[cfg(target_os = "linux")]
+struct SendmmsgBufs {
+    /// Headers with space for the packet info control message.
+    headers: nix::sys::socket::MultiHeaders<nix::sys::socket::SockaddrStorage>,
+    /// Headers without control messages. The messages with no `src_ip` can't use `headers` since
+    /// those keep the control message from the previous call.
+    headers_no_cmsg: nix::sys::socket::MultiHeaders<nix::sys::socket::SockaddrStorage>,
+    addrs: Vec<Option<nix::sys::socket::SockaddrStorage>>,
+}
+
+#[cfg(target_os = "linux")]
+thread_local! {
+    static SENDMMSG_BUFS: std::cell::RefCell<SendmmsgBufs> = std::cell::RefCell::new(SendmmsgBufs {
+        headers: nix::sys::socket::MultiHeaders::preallocate(BATCH_SIZE,
+            Some(nix::cmsg_space!(libc::in6_pktinfo))),
+        headers_no_cmsg: nix::sys::socket::MultiHeaders::preallocate(BATCH_SIZE, None),
+        addrs: Vec::with_capacity(BATCH_SIZE),
+    });
+}
+
+/// Sends `msgs` with a single `sendmmsg` call. All the messages must have the same `src_ip`.
+/// Returns the number of the messages sent, which is at most `BATCH_SIZE`.
+#[cfg(target_os = "linux")]
+fn sendmmsg(sock: &tokio::net::UdpSocket, msgs: &[Outgoing]) -> io::Result<usize> {
+    use std::io::IoSlice;
+    use std::os::fd::AsRawFd;
+
+    use nix::sys::socket::{ControlMessage, MsgFlags, SockaddrStorage};
+
+    let len = msgs.len().min(BATCH_SIZE);
+    let slices: [_; BATCH_SIZE] = std::array::from_fn(|i| {
+        [IoSlice::new(msgs.get(i).map(|v| &v.buf[..]).unwrap_or_default())]
+    });
+    let pktinfo4;
+    let pktinfo6;
+    let cmsg = match msgs[0].src_ip {
+        Some(IpAddr::V4(ip)) => {
+            pktinfo4 = libc::in_pktinfo {
+                ipi_ifindex: 0,
+                ipi_spec_dst: libc::in_addr { s_addr: u32::from_ne_bytes(ip.octets()) },
+                ipi_addr: libc::in_addr { s_addr: 0 },
+            };
+            Some(ControlMessage::Ipv4PacketInfo(&pktinfo4))
+        }
+        Some(IpAddr::V6(ip)) => {
+            pktinfo6 = libc::in6_pktinfo {
+                ipi6_addr: libc::in6_addr { s6_addr: ip.octets() },
+                ipi6_ifindex: 0,
+            };
+            Some(ControlMessage::Ipv6PacketInfo(&pktinfo6))
+        }
+        None => None,
+    };
+    SENDMMSG_BUFS.with_borrow_mut(|bufs| {
+        bufs.addrs.clear();
+        bufs.addrs.extend(msgs[..len].iter().map(|v| Some(SockaddrStorage::from(v.dst))));
+        let headers = if cmsg.is_some() {
+            &mut bufs.headers
+        } else {
+            &mut bufs.headers_no_cmsg
+        };
+        let r = nix::sys::socket::sendmmsg(sock.as_raw_fd(), headers, &slices[..len], &bufs.addrs,
+            cmsg.as_slice(), MsgFlags::empty())?;
+        Ok(r.count())
+    })
+}
+
+/// Response waiting to be sent.
+struct Outgoing {
+    buf: Vec<u8>,
+    dst: SocketAddr,
+    src_ip: Option<IpAddr>,
+}
+
+/// Pool of reusable buffers for the queries and the responses.
+#[derive(Default)]
+struct BufPool(Mutex<Vec<Vec<u8>>>);
+
+impl BufPool {
+    fn get(&self) -> Vec<u8> {
+        self.0.lock().pop().unwrap_or_default()
+    }
+
+    fn put(&self, mut buf: Vec<u8>) {
+        buf.clear();
+        let mut bufs = self.0.lock();
+        if bufs.len() < MAX_POOLED_BUFS {
+            bufs.push(buf);
+        }
+    }
+}
+
+/// Limits on the number of UDP queries processed concurrently and on the response rate, shared by
+/// the UDP sockets.
+#[derive(Clone)]
+pub struct Limit {
+    in_flight: Arc<Semaphore>,
+    overload_policy: OverloadPolicy,
+    rrl: Option<Arc<Rrl>>,
+    stats: Arc<Stats>,
+}
+
+impl Limit {
+    pub fn new(
+        max_in_flight: usize,
+        overload_policy: OverloadPolicy,
+        rrl: Option<Arc<Rrl>>,
+        stats: Arc<Stats>,
+    ) -> Self {
+        Self {
+            in_flight: Arc::new(Semaphore::new(max_in_flight)),
+            overload_policy,
+            rrl,
+            stats,
+        }
+    }
+}
+
+/// Serves queries received on `sock`. The responses are queued to a separate task that sends them
+/// in batches.
+pub async fn serve(sock: UdpSocket, processor: Processor, limit: Limit, mut tasks: Tasks) {
+    let sock = Arc::new(sock);
+    let pool = Arc::new(BufPool::default());
+
+    let (resp_tx, resp_rx) = mpsc::channel(SEND_QUEUE_LEN);
+    tokio::spawn(clone!(sock, pool, tasks => async move {
+        send_loop(&sock, resp_rx, &pool).await;
+        drop(tasks);
+    }));
+
+    let local_addr = match sock.local_addr() {
+        Ok(v) => v,
+        Err(err) => {
+            error!(?err, "error getting local address");
+            return;
+        }
+    };
+    let mut bufs = vec![vec![0; MAX_UDP_PACKET_LEN]; BATCH_SIZE];
+    let mut metas = [RecvMeta::default(); BATCH_SIZE];
+    loop {
+        let count = tokio::select! {
+            r = sock.recv(&mut bufs, &mut metas) => {
+                match r {
+                    Ok(v) => v,
+                    Err(err) => {
+                        error!(?err, "error reading from socket");
+                        break;
+                    }
+                }
+            }
+            _ = tasks.stopped() => {
+                break;
+            }
+        };
+        for (buf, meta) in bufs.iter().zip(metas).take(count) {
+            let msg = &buf[..meta.len];
+            let permit = if let Ok(v) = limit.in_flight.clone().try_acquire_owned() {
+                v
+            } else {
+                limit.stats.udp_queries_shed.fetch_add(1, Ordering::Relaxed);
+                shed(msg, meta.addr, meta.dst_ip, &pool, &resp_tx, limit.overload_policy,
+                    limit.rrl.as_deref());
+                continue;
+            };
+            let mut query = pool.get();
+            query.extend_from_slice(msg);
+            let origin = Origin {
+                client: meta.addr,
+                local: SocketAddr::new(meta.dst_ip.unwrap_or(local_addr.ip()), local_addr.port()),
+                transport: Transport::Udp,
+            };
+            let rrl = limit.rrl.clone();
+            tokio::spawn(clone!(pool, resp_tx, processor, tasks => async move {
+                received(origin,
+                    meta.dst_ip,
+                    query,
+                    &pool,
+                    &resp_tx,
+                    &processor,
+                    rrl.as_deref()).await;
+                drop(permit);
+                drop(tasks);
+            }));
+        }
+    }
+}
+
+async fn send_loop(sock: &UdpSocket, mut resp_rx: mpsc::Receiver<Outgoing>, pool: &BufPool) {
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    while resp_rx.recv_many(&mut batch, BATCH_SIZE).await > 0 {
+        sock.send_batch(&batch).await;
+        for v in batch.drain(..) {
+            debug!(len = v.buf.len(), dst = ?v.dst, "sent bytes");
+            pool.put(v.buf);
+        }
+    }
+}
+
+#[tracing::instrument(skip_all, fields(src = ?origin.client, ?dst_ip))]
+async fn received(
+    origin: Origin,
+    dst_ip: Option<IpAddr>,
+    msg: Vec<u8>,
+    pool: &BufPool,
+    resp_tx: &mpsc::Sender<Outgoing>,
+    processor: &Processor,
+    rrl: Option<&Rrl>,
+) {
+    debug!(len = msg.len(), "received bytes");
+
+    let mut resp = pool.get();
+    let outcome = handle(&msg, processor, &mut resp, origin, rrl).await;
+    pool.put(msg);
+    if !outcome.has_response() {
+        pool.put(resp);
+        return;
+    }
+    let _ = resp_tx.send(Outgoing {
+        buf: resp,
+        dst: origin.client,
+        src_ip: dst_ip,
+    }).await;
+}
+
+/// Handles the query that can't be processed due to overload. The response is subject to `rrl`
+/// and is dropped if the send queue is full.
+#[tracing::instrument(skip_all, fields(?src, ?dst_ip))]
+fn shed(
+    msg: &[u8],
+    src: SocketAddr,
+    dst_ip: Option<IpAddr>,
+    pool: &BufPool,
+    resp_tx: &mpsc::Sender<Outgoing>,
+    policy: OverloadPolicy,
+    rrl: Option<&Rrl>,
+) {
+    let code = match policy {
+        OverloadPolicy::Drop => {
+            debug!("overloaded, dropping query");
+            return;
+        }
+        OverloadPolicy::Refuse => RCODE_REFUSED,
+        OverloadPolicy::ServFail => RCODE_SERVER_FAILURE,
+    };
+    let query = match Packet::decode(msg) {
+        Ok(v) if v.kind == PacketKind::Query => v,
+        _ => return,
+    };
+    debug!(code, "overloaded, rejecting query");
+    let mut resp = query.to_response_with_code(code);
+    if let Some(rrl) = rrl {
+        match rrl.check(src.ip(), &resp) {
+            Verdict::Send => {}
+            Verdict::Drop => {
+                debug!("rate limited, dropping response");
+                return;
+            }
+            Verdict::Slip => {
+                debug!("rate limited, sending truncated response");
+                resp = rrl::slip(&resp);
+            }
+        }
+    }
+    let mut buf = pool.get();
+    if let Err(err) = resp.encode(&mut buf) {
+        error!(?err, "error encoding response");
+        pool.put(buf);
+        return;
+    }
+    let _ = resp_tx.try_send(Outgoing {
+        buf,
+        dst: src,
+        src_ip: dst_ip,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::dns::*;
+    use crate::server::{RrlConfig, Shutdown};
+    use crate::server::tests::{processor, query, recording_processor, tasks};
+
+    use super::*;
+
+    fn limit(overload_policy: OverloadPolicy) -> (Limit, Arc<Stats>) {
+        let stats = Arc::new(Stats::default());
+        (Limit::new(1, overload_policy, None, stats.clone()), stats)
+    }
+
+    async fn exchange(client: &tokio::net::UdpSocket, id: u16) -> Packet {
+        client.send(&query(id, "example.com")).await.unwrap();
+        recv(client).await
+    }
+
+    async fn recv(client: &tokio::net::UdpSocket) -> Packet {
+        let mut buf = [0; 512];
+        let len = tokio::time::timeout(Duration::from_secs(1), client.recv(&mut buf)).await
+            .expect("no response").unwrap();
+        Packet::decode(&buf[..len]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn response_source_address() {
+        let sock = UdpSocket::bind("0.0.0.0:0".parse().unwrap(), false).unwrap();
+        let port = sock.local_addr().unwrap().port();
+        let (processor, origins) = recording_processor();
+        tokio::spawn(serve(sock, processor, limit(OverloadPolicy::Drop).0, tasks()));
+
+        // Connected socket only accepts datagrams coming from the address it's connected to.
+        for ip in ["127.0.0.1", "127.0.0.2"] {
+            let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.connect((ip, port)).await.unwrap();
+            assert_eq!(exchange(&client, 1).await.id, 1);
+            assert_eq!(origins.lock().pop(), Some(Origin {
+                client: client.local_addr().unwrap(),
+                local: SocketAddr::new(ip.parse().unwrap(), port),
+                transport: Transport::Udp,
+            }));
+        }
+    }
+
+    #[tokio::test]
+    async fn dual_stack() {
+        let sock4 = UdpSocket::bind("0.0.0.0:0".parse().unwrap(), false).unwrap();
+        let port = sock4.local_addr().unwrap().port();
+        let sock6 = UdpSocket::bind(SocketAddr::new("::".parse().unwrap(), port), false).unwrap();
+        tokio::spawn(serve(sock4, processor(), limit(OverloadPolicy::Drop).0, tasks()));
+        tokio::spawn(serve(sock6, processor(), limit(OverloadPolicy::Drop).0, tasks()));
+
+        for (bind, ip) in [("127.0.0.1:0", "127.0.0.1"), ("[::1]:0", "::1")] {
+            let client = tokio::net::UdpSocket::bind(bind).await.unwrap();
+            client.connect((ip, port)).await.unwrap();
+            assert_eq!(exchange(&client, 2).await.id, 2);
+        }
+    }
+
+    #[tokio::test]
+    async fn reuse_port_batches() {
+        let sock = UdpSocket::bind("127.0.0.1:0".parse().unwrap(), true).unwrap();
+        let addr = sock.local_addr().unwrap();
+        let socks = [sock, UdpSocket::bind(addr, true).unwrap()];
+        let stats = Arc::new(Stats::default());
+        let limit = Limit::new(1000, OverloadPolicy::Drop, None, stats);
+        for sock in socks {
+            tokio::spawn(serve(sock, processor(), limit.clone(), tasks()));
+        }
+
+        // Enough queries from a number of clients to fill the batches on all the sockets.
+        let mut clients = Vec::new();
+        for _ in 0..8 {
+            let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.connect(addr).await.unwrap();
+            for id in 0..2 * BATCH_SIZE as u16 {
+                client.send(&query(id, "example.com")).await.unwrap();
+            }
+            clients.push(client);
+        }
+        for client in &clients {
+            let mut ids = Vec::new();
+            for _ in 0..2 * BATCH_SIZE {
+                ids.push(recv(client).await.id);
+            }
+            ids.sort_unstable();
+            assert_eq!(ids, (0..2 * BATCH_SIZE as u16).collect::<Vec<_>>());
+        }
+    }
+
+    #[tokio::test]
+    async fn overload() {
+        for policy in [OverloadPolicy::Drop, OverloadPolicy::Refuse, OverloadPolicy::ServFail] {
+            let sock = UdpSocket::bind("127.0.0.1:0".parse().unwrap(), false).unwrap();
+            let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.connect(sock.local_addr().unwrap()).await.unwrap();
+            let (limit, stats) = limit(policy);
+            tokio::spawn(serve(sock, processor(), limit, tasks()));
+
+            client.send(&query(1, "slow.example.com")).await.unwrap();
+            client.send(&query(2, "example.com")).await.unwrap();
+            if policy != OverloadPolicy::Drop {
+                let resp = recv(&client).await;
+                assert_eq!(resp.id, 2);
+                assert_eq!(resp.response_code, if policy == OverloadPolicy::Refuse {
+                    RCODE_REFUSED
+                } else {
+                    RCODE_SERVER_FAILURE
+                });
+            }
+            let resp = recv(&client).await;
+            assert_eq!(resp.id, 1);
+            assert_eq!(resp.response_code, RCODE_NX_DOMAIN);
+            assert_eq!(stats.udp_queries_shed.load(Ordering::Relaxed), 1);
+
+            // The limit is released.
+            assert_eq!(exchange(&client, 3).await.id, 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn rrl() {
+        let sock = UdpSocket::bind("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(sock.local_addr().unwrap()).await.unwrap();
+        let stats = Arc::new(Stats::default());
+        let rrl = Rrl::new(RrlConfig {
+            responses_per_sec: 1,
+            slip: 2,
+            ..Default::default()
+        }, stats.clone());
+        let limit = Limit::new(10, OverloadPolicy::Drop, Some(Arc::new(rrl)), stats.clone());
+        tokio::spawn(serve(sock, processor(), limit, tasks()));
+
+        let resp = exchange(&client, 1).await;
+        assert!(!resp.truncated);
+        // Dropped.
+        client.send(&query(2, "example.com")).await.unwrap();
+        let resp = exchange(&client, 3).await;
+        assert_eq!(resp.id, 3);
+        assert!(resp.truncated);
+        assert_eq!(resp.response_code, RCODE_NX_DOMAIN);
+        assert_eq!(stats.rrl_responses_dropped.load(Ordering::Relaxed), 1);
+        assert_eq!(stats.rrl_responses_slipped.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn overload_rrl() {
+        let sock = UdpSocket::bind("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(sock.local_addr().unwrap()).await.unwrap();
+        let stats = Arc::new(Stats::default());
+        let rrl = Rrl::new(RrlConfig {
+            responses_per_sec: 1,
+            slip: 0,
+            ..Default::default()
+        }, stats.clone());
+        let limit = Limit::new(1, OverloadPolicy::Refuse, Some(Arc::new(rrl)), stats.clone());
+        tokio::spawn(serve(sock, processor(), limit, tasks()));
+
+        client.send(&query(1, "slow.example.com")).await.unwrap();
+        for id in 2..5 {
+            client.send(&query(id, "example.com")).await.unwrap();
+        }
+        let resp = recv(&client).await;
+        assert_eq!(resp.id, 2);
+        assert_eq!(resp.response_code, RCODE_REFUSED);
+        assert_eq!(recv(&client).await.id, 1);
+        assert_eq!(stats.udp_queries_shed.load(Ordering::Relaxed), 3);
+        assert_eq!(stats.rrl_responses_dropped.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        async fn start() -> (Shutdown, tokio::net::UdpSocket) {
+            let (shutdown, tasks) = Shutdown::new();
+            let sock = UdpSocket::bind("127.0.0.1:0".parse().unwrap(), false).unwrap();
+            let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.connect(sock.local_addr().unwrap()).await.unwrap();
+            tokio::spawn(serve(sock, processor(), limit(OverloadPolicy::Drop).0, tasks));
+            (shutdown, client)
+        }
+
+        let (shutdown, client) = start().await;
+        client.send(&query(1, "slow.example.com")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let shutdown = tokio::spawn(shutdown.run(Duration::from_secs(1)));
+        let mut buf = [0; 512];
+        let len = client.recv(&mut buf).await.unwrap();
+        assert_eq!(Packet::decode(&buf[..len]).unwrap().id, 1);
+        assert!(shutdown.await.unwrap());
+
+        // No longer receiving, the socket is closed.
+        client.send(&query(2, "example.com")).await.unwrap();
+        let r = tokio::time::timeout(Duration::from_millis(100), client.recv(&mut buf)).await;
+        assert!(!matches!(r, Ok(Ok(_))));
+
+        // In-flight query outlives the deadline.
+        let (shutdown, client) = start().await;
+        client.send(&query(1, "slow.example.com")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!shutdown.run(Duration::from_millis(10)).await);
+    }
+}
@@ -129,7 +129,7 @@ impl UpstreamServer {
         query.recursion_desired = true;
 
         let mut buf = Vec::new();
-        query.encode(&mut buf);
+        query.encode(&mut buf)?;
         debug!(?query, len = buf.len(), "sending query");
         sock.send_to(&buf, self.addr).await?;
 